@@ -0,0 +1,203 @@
+//! Resolution of the image files referenced by templates.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AssetError {
+    #[error("Absolute image paths are not allowed: {0}")]
+    Absolute(String),
+    #[error("Image path leaves the asset root: {0}")]
+    Escape(String),
+    #[error("Unknown image asset: {0}")]
+    Unknown(String),
+    #[error("Cannot read image {0}: {1}")]
+    Io(String, std::io::Error),
+}
+
+/// The files `img()` cells may read.
+#[derive(Debug, Clone, Default)]
+pub enum FileAccess {
+    /// Any path, relative to the working directory.
+    #[default]
+    Any,
+    /// Relative paths below this directory only.
+    Root(PathBuf),
+    /// No files, only the in-memory assets.
+    Nothing,
+}
+
+/// Finds the images of `img()` cells by their path. In-memory assets are
+/// looked up first, then the files allowed by `files`.
+#[derive(Debug, Clone, Default)]
+pub struct AssetResolver {
+    pub files: FileAccess,
+    /// Image data by the path used in templates, e.g. `logos/acme.png`.
+    pub assets: HashMap<String, Vec<u8>>,
+}
+
+impl AssetResolver {
+    /// Reads images from relative paths below `root`, rejecting absolute
+    /// paths and paths leaving it.
+    pub fn sandboxed<P: Into<PathBuf>>(root: P) -> Self {
+        AssetResolver {
+            files: FileAccess::Root(root.into()),
+            assets: HashMap::new(),
+        }
+    }
+
+    /// Serves only the images added with [`AssetResolver::with_asset`].
+    pub fn in_memory() -> Self {
+        AssetResolver {
+            files: FileAccess::Nothing,
+            assets: HashMap::new(),
+        }
+    }
+
+    pub fn with_asset(mut self, path: &str, data: Vec<u8>) -> Self {
+        self.assets.insert(path.to_string(), data);
+        self
+    }
+
+    /// The image data for a path of a template.
+    pub fn resolve(&self, path: &str) -> Result<Cow<'_, [u8]>, AssetError> {
+        if let Some(data) = self.assets.get(path) {
+            return Ok(Cow::Borrowed(data));
+        }
+        let file = match &self.files {
+            FileAccess::Any => PathBuf::from(path),
+            FileAccess::Root(root) => sandboxed_path(root, path)?,
+            FileAccess::Nothing => return Err(AssetError::Unknown(path.to_string())),
+        };
+        fs::read(file)
+            .map(Cow::Owned)
+            .map_err(|e| AssetError::Io(path.to_string(), e))
+    }
+}
+
+/// Joins a relative path to the root. Symbolic links are followed, so the
+/// file must also really be below the root.
+fn sandboxed_path(root: &Path, path: &str) -> Result<PathBuf, AssetError> {
+    for component in Path::new(path).components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => {
+                return Err(AssetError::Absolute(path.to_string()))
+            }
+            Component::ParentDir => return Err(AssetError::Escape(path.to_string())),
+            Component::CurDir | Component::Normal(_) => {}
+        }
+    }
+    let io_error = |e| AssetError::Io(path.to_string(), e);
+    let root = root.canonicalize().map_err(io_error)?;
+    let file = root.join(path).canonicalize().map_err(io_error)?;
+    if file.starts_with(&root) {
+        Ok(file)
+    } else {
+        Err(AssetError::Escape(path.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory holding `root/logo.png` and `outside.png` next to it.
+    fn fixture(name: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!(
+            "spreadsheet_builder_assets_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("sub").join("logo.png"), b"logo").unwrap();
+        fs::write(dir.join("outside.png"), b"outside").unwrap();
+        (dir, root)
+    }
+
+    #[test]
+    fn reads_relative_paths_below_the_root() {
+        let (dir, root) = fixture("relative");
+        let resolver = AssetResolver::sandboxed(&root);
+        assert_eq!(&*resolver.resolve("sub/logo.png").unwrap(), b"logo");
+        assert_eq!(&*resolver.resolve("./sub/logo.png").unwrap(), b"logo");
+        assert!(matches!(
+            resolver.resolve("sub/missing.png"),
+            Err(AssetError::Io(..))
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        let (dir, root) = fixture("absolute");
+        let resolver = AssetResolver::sandboxed(&root);
+        let absolute = root.join("sub").join("logo.png");
+        assert!(matches!(
+            resolver.resolve(absolute.to_str().unwrap()),
+            Err(AssetError::Absolute(_))
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_parent_components() {
+        let (dir, root) = fixture("parent");
+        let resolver = AssetResolver::sandboxed(&root);
+        for path in [
+            "../outside.png",
+            "sub/../../outside.png",
+            "sub/../sub/logo.png",
+        ] {
+            assert!(matches!(resolver.resolve(path), Err(AssetError::Escape(_))));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlinks_leaving_the_root() {
+        let (dir, root) = fixture("symlink");
+        std::os::unix::fs::symlink(dir.join("outside.png"), root.join("link.png")).unwrap();
+        std::os::unix::fs::symlink(root.join("sub"), root.join("inside")).unwrap();
+        let resolver = AssetResolver::sandboxed(&root);
+        assert!(matches!(
+            resolver.resolve("link.png"),
+            Err(AssetError::Escape(_))
+        ));
+        assert_eq!(&*resolver.resolve("inside/logo.png").unwrap(), b"logo");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn in_memory_assets_come_first() {
+        let (dir, root) = fixture("memory");
+        let resolver =
+            AssetResolver::sandboxed(&root).with_asset("sub/logo.png", b"memory".to_vec());
+        assert_eq!(&*resolver.resolve("sub/logo.png").unwrap(), b"memory");
+        // The map is looked up by the exact path, even one the sandbox rejects.
+        let resolver = resolver.with_asset("/logo.png", b"absolute".to_vec());
+        assert_eq!(&*resolver.resolve("/logo.png").unwrap(), b"absolute");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn in_memory_resolver_reads_no_files() {
+        let (dir, root) = fixture("nothing");
+        let resolver = AssetResolver::in_memory().with_asset("logo.png", b"memory".to_vec());
+        assert!(matches!(resolver.files, FileAccess::Nothing));
+        assert_eq!(&*resolver.resolve("logo.png").unwrap(), b"memory");
+        let file = root.join("sub").join("logo.png");
+        for path in [file.to_str().unwrap(), "sub/logo.png", "../outside.png"] {
+            assert!(matches!(
+                resolver.resolve(path),
+                Err(AssetError::Unknown(_))
+            ));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
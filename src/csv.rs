@@ -1,76 +1,493 @@
-use crate::engine::ast::{CellType, Element, Row, RowItem};
+use crate::engine::ast::{Cell, CellType, Defaults, Element, Format, Row, Table, TotalsItem};
 use crate::engine::diag::SpreadSheetError;
+use crate::engine::layout::Layout;
 use crate::engine::vm::SheetProcessor;
-use csv::{Writer, WriterBuilder};
+use crate::numfmt::{format_date, format_general, format_number, DateTime};
+use crate::sanitize::escape_formula;
+use csv::WriterBuilder;
+pub use csv::{QuoteStyle, Terminator};
+use ecow::EcoString;
+use indexmap::IndexMap;
+use std::collections::{BTreeMap, HashSet};
 use std::fs::File;
+use std::io::Write;
 use std::path::Path;
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+/// Where the rendered sheets end up.
+pub enum CsvOutput {
+    /// Every sheet is written into the same file, one after the other.
+    Single(File),
+    /// Every sheet is written into its own file. The pattern is the path of
+    /// the files, `{sheet}` is replaced with the name of the sheet.
+    PerSheet(String),
+    /// Every sheet is written as an entry of a zip archive. The pattern is the
+    /// name of the entries, `{sheet}` is replaced with the name of the sheet.
+    /// The archive is taken out and closed when the writer is saved.
+    Zip(Option<Box<ZipWriter<File>>>, String),
+}
+
+/// What to write for `img()` cells.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageOutput {
+    /// Leave the cell empty.
+    Nothing,
+    /// Write the image source, e.g. its path.
+    Source,
+}
+
+/// The CSV dialect and the text rendering of values.
+#[derive(Debug, Clone)]
+pub struct CsvOptions {
+    pub delimiter: u8,
+    pub quote_style: QuoteStyle,
+    pub terminator: Terminator,
+    /// Start every file with a UTF-8 byte order mark, for Excel.
+    pub bom: bool,
+    /// The decimal separator of numbers. With `,` the thousands separator
+    /// of format patterns becomes `.`.
+    pub decimal_separator: char,
+    /// An Excel style pattern for `date()` cells, e.g. `yyyy-mm-dd`. Dates
+    /// are written as given in the template when not set.
+    pub date_format: Option<String>,
+    /// Render `num()` and `date()` cells with the `num("...")` pattern of
+    /// their format declaration. Patterns with fractions or conditions, which
+    /// text cannot follow, leave numbers in the general format and dates as
+    /// they are written.
+    pub use_formats: bool,
+    pub images: ImageOutput,
+    /// Prefix `str()` values starting with `=`, `+`, `-` or `@` with a
+    /// single quote, so they do not become formulas when opened in Excel.
+    pub protect_formulas: bool,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            delimiter: b',',
+            quote_style: QuoteStyle::Necessary,
+            terminator: Terminator::Any(b'\n'),
+            bom: false,
+            decimal_separator: '.',
+            date_format: None,
+            use_formats: false,
+            images: ImageOutput::Nothing,
+            protect_formulas: false,
+        }
+    }
+}
 
 pub struct CsvWriter {
-    pub writer: Writer<File>,
+    pub output: CsvOutput,
+    pub options: CsvOptions,
+    /// The `num("...")` patterns of the declared formats.
+    pub formats: IndexMap<EcoString, String>,
+    /// The patterns of `num()` and `date()` cells without a format of
+    /// their own, set by the `defaults` block.
+    pub number_pattern: Option<String>,
+    pub date_pattern: Option<String>,
+    /// Whether anything has been written to the output yet.
+    pub started: bool,
+    /// The name of the current sheet.
+    pub sheet: Option<String>,
+    /// The cells of the current sheet, keyed by row and then by column.
+    pub grid: BTreeMap<u32, BTreeMap<u16, String>>,
+    /// The files or zip entries written so far, so that no sheet replaces
+    /// another one.
+    pub written: HashSet<String>,
 }
 
 impl CsvWriter {
+    pub fn new(output: CsvOutput, delimiter: u8) -> Self {
+        CsvWriter {
+            output,
+            options: CsvOptions {
+                delimiter,
+                ..Default::default()
+            },
+            formats: IndexMap::new(),
+            number_pattern: None,
+            date_pattern: None,
+            started: false,
+            sheet: None,
+            grid: BTreeMap::new(),
+            written: HashSet::new(),
+        }
+    }
+
+    pub fn with_options(mut self, options: CsvOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Writes all sheets into a single file.
     pub fn from_path<P: AsRef<Path>>(path: P, delimiter: u8) -> Result<Self, csv::Error> {
-        let writer = WriterBuilder::new().delimiter(delimiter).from_path(path)?;
-        Ok(CsvWriter { writer })
+        let file = File::create(path)?;
+        Ok(Self::new(CsvOutput::Single(file), delimiter))
+    }
+
+    /// Writes every sheet into its own file, e.g. `out/{sheet}.csv`.
+    pub fn per_sheet(pattern: &str, delimiter: u8) -> Self {
+        Self::new(CsvOutput::PerSheet(pattern.to_string()), delimiter)
+    }
+
+    /// Packs the sheets into a zip archive, with entries named like `{sheet}.csv`.
+    pub fn zip<P: AsRef<Path>>(path: P, pattern: &str, delimiter: u8) -> Result<Self, csv::Error> {
+        let archive = ZipWriter::new(File::create(path)?);
+        Ok(Self::new(
+            CsvOutput::Zip(Some(Box::new(archive)), pattern.to_string()),
+            delimiter,
+        ))
     }
 
     pub fn save(&mut self) -> Result<(), csv::Error> {
-        self.writer.flush()?;
+        self.flush_grid()?;
+        match &mut self.output {
+            CsvOutput::Single(file) => file.flush()?,
+            CsvOutput::PerSheet(_) => {}
+            CsvOutput::Zip(archive, _) => {
+                if let Some(archive) = archive.take() {
+                    archive.finish().map_err(std::io::Error::from)?;
+                }
+            }
+        }
         Ok(())
     }
 
-    pub fn process_internal(&mut self, item: &Element) -> Result<(), csv::Error> {
+    pub fn process_internal(&mut self, item: &Element, layout: &Layout) -> Result<(), csv::Error> {
         // println!("processing item {:?}", item);
-        if let Element::Row(row) = item {
-            self.process_row(row)?;
+        match item {
+            Element::Sheet(sheet) => {
+                self.flush_grid()?;
+                self.sheet = Some(sheet.name.clone());
+            }
+            Element::Row(row) => {
+                self.process_row(row, layout);
+            }
+            Element::Format(format) => {
+                self.process_format(format);
+            }
+            Element::Defaults(defaults) => {
+                self.process_defaults(defaults);
+            }
+            Element::Table(table) => {
+                self.process_table(table);
+            }
+            _ => {}
         }
 
         Ok(())
     }
 
-    pub fn process_row(&mut self, row: &Row) -> Result<(), csv::Error> {
-        for item in &row.cells {
-            let cell = match item {
-                RowItem::Cell(cell) => cell,
-                RowItem::ForEachCell(_) => continue,
-                RowItem::ForEachHeader(_) => continue,
-            };
-            match cell.cell_type {
-                CellType::Num => {
-                    self.writer.write_field(cell.value.as_f64().to_string())?;
-                }
-                CellType::Str => {
-                    self.writer.write_field(cell.value.as_str())?;
-                }
-                CellType::Date => {
-                    self.writer.write_field(cell.value.as_str())?;
-                }
-                CellType::Image => {
-                    // ignore
-                }
-                CellType::Bool => {
-                    self.writer.write_field(cell.value.as_str())?;
-                }
+    pub fn process_format(&mut self, format: &Format) {
+        for modifier in &format.modifiers {
+            if modifier.statement == "num" {
+                self.formats.insert(
+                    EcoString::from(format.identifier),
+                    modifier.expression.as_str(),
+                );
             }
-            for _ in 1..cell.colspan {
-                self.writer.write_field("")?;
+        }
+    }
+
+    pub fn process_defaults(&mut self, defaults: &Defaults) {
+        for default in &defaults.formats {
+            let pattern = self.formats.get(default.format).cloned();
+            match default.kind {
+                "num" => self.number_pattern = pattern,
+                "date" => self.date_pattern = pattern,
+                _ => {}
+            }
+        }
+    }
+
+    /// The rows of a table are plain rows already, only the labels of the
+    /// totals row are added. Total functions are left to Excel.
+    pub fn process_table(&mut self, table: &Table) {
+        let Some((_, first_col, last_row, _)) = table.region else {
+            return;
+        };
+        for (col, item) in (first_col..).zip(&table.totals) {
+            if let TotalsItem::Label(label) = item {
+                self.grid
+                    .entry(last_row)
+                    .or_default()
+                    .insert(col, label.as_str());
+            }
+        }
+    }
+
+    pub fn process_row(&mut self, row: &Row, layout: &Layout) {
+        for placed in layout.place(row) {
+            if let Some(text) = self.render_cell(placed.cell) {
+                self.grid
+                    .entry(placed.row)
+                    .or_default()
+                    .insert(placed.col, text);
             }
         }
+    }
 
-        self.writer.write_record(None::<&[u8]>)?;
+    /// Renders the buffered sheet, hands it to the output and clears it.
+    ///
+    /// A sheet without cells still produces an (empty) file or zip entry, so
+    /// the sheets of the template are never lost. Two sheets are refused the
+    /// same file or entry, e.g. `Q1/Q2` and `Q1_Q2`, or any two sheets with a
+    /// pattern without `{sheet}`.
+    fn flush_grid(&mut self) -> Result<(), csv::Error> {
+        let grid = std::mem::take(&mut self.grid);
+        let sheet = self.sheet.take();
+        if sheet.is_none() && grid.is_empty() {
+            return Ok(());
+        }
+        let mut data = self.render_grid(&grid)?;
+        let first = !self.started || !matches!(self.output, CsvOutput::Single(_));
+        if self.options.bom && first {
+            data.splice(0..0, [0xEF, 0xBB, 0xBF]);
+        }
+        self.started = true;
+        let name = sheet_file_name(sheet.as_deref().unwrap_or("sheet"));
+
+        match &mut self.output {
+            CsvOutput::Single(file) => {
+                file.write_all(&data)?;
+            }
+            CsvOutput::PerSheet(pattern) => {
+                let path = pattern.replace("{sheet}", &name);
+                claim(&mut self.written, &path)?;
+                if let Some(parent) = Path::new(&path).parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(path, data)?;
+            }
+            CsvOutput::Zip(archive, pattern) => {
+                let archive = archive.as_mut().ok_or_else(|| {
+                    std::io::Error::other("the zip archive has already been saved")
+                })?;
+                let entry = pattern.replace("{sheet}", &name);
+                claim(&mut self.written, &entry)?;
+                archive
+                    .start_file(entry, SimpleFileOptions::default())
+                    .map_err(std::io::Error::from)?;
+                archive.write_all(&data)?;
+            }
+        }
 
         Ok(())
     }
+
+    /// Writes the grid as a dense block of records.
+    ///
+    /// Every record is as wide as the widest row of the sheet, positions
+    /// without a cell (including the area covered by spans) are left empty.
+    fn render_grid(
+        &self,
+        grid: &BTreeMap<u32, BTreeMap<u16, String>>,
+    ) -> Result<Vec<u8>, csv::Error> {
+        let mut writer = WriterBuilder::new()
+            .delimiter(self.options.delimiter)
+            .quote_style(self.options.quote_style)
+            .terminator(self.options.terminator)
+            .from_writer(Vec::new());
+        let Some(last_row) = grid.keys().next_back().copied() else {
+            return Ok(Vec::new());
+        };
+        let width = grid
+            .values()
+            .filter_map(|cols| cols.keys().next_back())
+            .map(|col| *col as usize + 1)
+            .max()
+            .unwrap_or(0);
+
+        for row in 0..=last_row {
+            let cols = grid.get(&row);
+            for col in 0..width {
+                let field = cols
+                    .and_then(|cols| cols.get(&(col as u16)))
+                    .map(String::as_str)
+                    .unwrap_or("");
+                writer.write_field(field)?;
+            }
+            writer.write_record(None::<&[u8]>)?;
+        }
+
+        writer
+            .into_inner()
+            .map_err(|e| csv::Error::from(e.into_error()))
+    }
+
+    /// The `num("...")` pattern of the cell's format, or of the default format
+    /// of its type, if formats are honoured.
+    fn pattern(&self, cell: &Cell) -> Option<&str> {
+        if !self.options.use_formats {
+            return None;
+        }
+        match cell.format {
+            Some(format) => self.formats.get(format),
+            None => match cell.cell_type {
+                CellType::Num => self.number_pattern.as_ref(),
+                CellType::Date => self.date_pattern.as_ref(),
+                _ => None,
+            },
+        }
+        .map(String::as_str)
+    }
+
+    fn render_cell(&self, cell: &Cell) -> Option<String> {
+        let separator = self.options.decimal_separator;
+        let text = match cell.cell_type {
+            CellType::Num => {
+                let value = cell.value.as_f64();
+                return Some(match self.pattern(cell) {
+                    Some(pattern) => format_number(value, pattern, separator),
+                    None => format_general(value, separator),
+                });
+            }
+            CellType::Str | CellType::Url | CellType::Rich => match (&cell.cell_type, &cell.text) {
+                (CellType::Url, Some(text)) => text.as_str(),
+                _ => cell.value.as_str(),
+            },
+            CellType::Date => {
+                let text = cell.value.as_str();
+                let pattern = self.pattern(cell).or(self.options.date_format.as_deref());
+                // Dates the pattern cannot render, e.g. a time alone with a
+                // pattern of days, are written as they are.
+                let formatted = pattern
+                    .zip(DateTime::parse(&text))
+                    .and_then(|(pattern, date)| format_date(&date, pattern));
+                match formatted {
+                    Some(formatted) => return Some(formatted),
+                    None => text,
+                }
+            }
+            CellType::Image => match self.options.images {
+                ImageOutput::Nothing => return None,
+                ImageOutput::Source => cell.value.as_str(),
+            },
+            CellType::Bool => cell.value.as_str(),
+        };
+        if self.options.protect_formulas {
+            Some(escape_formula(text))
+        } else {
+            Some(text)
+        }
+    }
+}
+
+/// Makes a sheet name safe to use as a single path component.
+fn sheet_file_name(name: &str) -> String {
+    // `.` and `..` would name the output directory or its parent.
+    match name {
+        "" => return "sheet".to_string(),
+        "." | ".." => return name.replace('.', "_"),
+        _ => {}
+    }
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
 }
 
 impl SheetProcessor for CsvWriter {
-    fn process(&mut self, item: &Element) -> Result<(), SpreadSheetError> {
-        self.process_internal(item).map_err(handle_error)
+    fn process(&mut self, item: &Element, layout: &Layout) -> Result<(), SpreadSheetError> {
+        self.process_internal(item, layout).map_err(handle_error)
     }
 }
 
+/// Records a file or zip entry, unless a sheet has already been written there.
+fn claim(written: &mut HashSet<String>, path: &str) -> Result<(), std::io::Error> {
+    if !written.insert(path.to_string()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("Two sheets would be written to {}", path),
+        ));
+    }
+    Ok(())
+}
+
 fn handle_error(e: csv::Error) -> SpreadSheetError {
     let msg = format!("{:?}", e);
     SpreadSheetError::new(msg)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::parser::parse_stream;
+    use crate::engine::vm::VM;
+
+    /// The CSV text of a template written into a single file.
+    fn render(name: &str, template: &str, options: CsvOptions) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "spreadsheet_builder_csv_{}_{}.csv",
+            name,
+            std::process::id()
+        ));
+        let tree = parse_stream(template).unwrap();
+        let mut writer = CsvWriter::from_path(&path, b',')
+            .unwrap()
+            .with_options(options);
+        VM::default().run(&tree.elements, &mut writer).unwrap();
+        writer.save().unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        text
+    }
+
+    #[test]
+    fn times_without_a_date_keep_their_text() {
+        let template = r#"
+sheet("S")
+[ date("12:30"), date("2024-03-05") ]
+"#;
+        let options = CsvOptions {
+            date_format: Some("dd mmm yyyy".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(render("time", template, options), "12:30,05 Mar 2024\n");
+    }
+
+    #[test]
+    fn sheets_never_replace_each_other() {
+        let dir = std::env::temp_dir().join(format!(
+            "spreadsheet_builder_csv_collisions_{}",
+            std::process::id()
+        ));
+        let write = |pattern: &str, template: &str| {
+            let pattern = dir.join(pattern);
+            let tree = parse_stream(template).unwrap();
+            let mut writer = CsvWriter::per_sheet(pattern.to_str().unwrap(), b',');
+            VM::default()
+                .run(&tree.elements, &mut writer)
+                .map_err(|e| e.to_string())
+                .and_then(|_| writer.save().map_err(|e| format!("{:?}", e)))
+        };
+
+        write("{sheet}.csv", "sheet(\"A\")\n[ num(1) ]\nsheet(\"B\")\n").unwrap();
+        for (pattern, template) in [
+            ("{sheet}.csv", "sheet(\"Q1/Q2\")\nsheet(\"Q1_Q2\")\n"),
+            ("out.csv", "sheet(\"A\")\nsheet(\"B\")\n"),
+            ("{sheet}.csv", "sheet(\"\")\nsheet(\"sheet\")\n"),
+        ] {
+            let error = write(pattern, template).unwrap_err();
+            assert!(error.contains("Two sheets would be written"), "{}", error);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sheet_file_names_stay_in_the_directory() {
+        assert_eq!(sheet_file_name("Data"), "Data");
+        assert_eq!(sheet_file_name("Q1/Q2"), "Q1_Q2");
+        assert_eq!(sheet_file_name("..\\x"), ".._x");
+        assert_eq!(sheet_file_name("."), "_");
+        assert_eq!(sheet_file_name(".."), "__");
+        assert_eq!(sheet_file_name("..."), "...");
+        assert_eq!(sheet_file_name("a\0b"), "a_b");
+        assert_eq!(sheet_file_name(""), "sheet");
+    }
+}
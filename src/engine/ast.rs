@@ -12,14 +12,47 @@ pub struct Format<'a> {
     pub modifiers: Vec<Modifier<'a>>,
 }
 
+#[derive(Debug)]
+pub struct DefaultFormat<'a> {
+    pub kind: &'a str,
+    pub format: &'a str,
+}
+
+/// The formats of the `num()`, `date()` and `str()` cells without a
+/// format of their own.
+#[derive(Debug)]
+pub struct Defaults<'a> {
+    pub formats: Vec<DefaultFormat<'a>>,
+}
+
 #[derive(Debug)]
 pub struct Anchor<'a> {
-    pub identifier: &'a str,
+    pub name: Expr<'a>,
 }
 
 #[derive(Debug)]
-pub struct Sheet {
+pub struct Property<'a> {
+    pub kind: &'a str,
+    pub args: Vec<Expr<'a>>,
+}
+
+/// The document properties of the workbook.
+#[derive(Debug)]
+pub struct Properties<'a> {
+    pub properties: Vec<Property<'a>>,
+}
+
+#[derive(Debug)]
+pub enum SheetOption<'a> {
+    /// Freeze the rows above and the columns left of a cell.
+    Freeze(CellRef<'a>),
+    Setting(&'a str, Option<Expr<'a>>),
+}
+
+#[derive(Debug)]
+pub struct Sheet<'a> {
     pub name: String,
+    pub options: Vec<SheetOption<'a>>,
 }
 
 #[derive(Debug)]
@@ -61,18 +94,230 @@ pub enum CellType {
     Date,
     Image,
     Bool,
+    Url,
+    Rich,
+}
+
+/// A run of text in a `rich()` cell, with its own format.
+#[derive(Debug)]
+pub struct RichSegment<'a> {
+    pub text: Expr<'a>,
+    pub format: Option<&'a str>,
+}
+
+#[derive(Debug)]
+pub struct Note<'a> {
+    pub text: Expr<'a>,
+    pub author: Option<Expr<'a>>,
+    pub visible: bool,
+}
+
+/// A placement or description setting of an `img()` cell, e.g. `alt: "Logo"`.
+#[derive(Debug)]
+pub struct ImageOption<'a> {
+    pub kind: &'a str,
+    pub value: Expr<'a>,
 }
 
 #[derive(Debug)]
 pub struct Cell<'a> {
     pub cell_type: CellType,
     pub value: Expr<'a>,
+    /// The displayed text of a `url()` cell.
+    pub text: Option<Expr<'a>>,
+    /// The segments of a `rich()` cell. The VM sets the value to their
+    /// concatenated text.
+    pub rich: Vec<RichSegment<'a>>,
     pub format: Option<&'a str>,
     pub colspan: u16,
     pub rowspan: u16,
     pub image_mode: Option<&'a str>,
+    pub image_options: Vec<ImageOption<'a>>,
+    pub note: Option<Note<'a>>,
 }
 
+/// A cell addressed relative to an anchor.
+#[derive(Debug)]
+pub struct CellRef<'a> {
+    pub anchor: Expr<'a>,
+    pub row: i32,
+    pub col: i16,
+}
+
+/// A single cell, or the rectangle between two cells.
+#[derive(Debug)]
+pub struct Range<'a> {
+    pub from: CellRef<'a>,
+    pub to: Option<CellRef<'a>>,
+}
+
+#[derive(Debug)]
+pub enum ConditionTest<'a> {
+    Cell(CompareOp, Expr<'a>),
+    Between {
+        negated: bool,
+        low: Expr<'a>,
+        high: Expr<'a>,
+    },
+    Text(&'a str, Expr<'a>),
+    Top {
+        bottom: bool,
+        rank: u16,
+        percent: bool,
+    },
+    Duplicate {
+        unique: bool,
+    },
+}
+
+#[derive(Debug)]
+pub enum ConditionalRule<'a> {
+    When(ConditionTest<'a>, &'a str),
+    DataBar(Option<Expr<'a>>),
+    ColorScale2(Vec<Expr<'a>>),
+    ColorScale3(Vec<Expr<'a>>),
+    IconSet(Expr<'a>),
+}
+
+#[derive(Debug)]
+pub struct Conditional<'a> {
+    pub range: Range<'a>,
+    pub rules: Vec<ConditionalRule<'a>>,
+}
+
+#[derive(Debug)]
+pub struct ValidationRule<'a> {
+    pub kind: &'a str,
+    pub args: Vec<Expr<'a>>,
+}
+
+#[derive(Debug)]
+pub struct Validation<'a> {
+    pub range: Range<'a>,
+    pub rules: Vec<ValidationRule<'a>>,
+}
+
+#[derive(Debug)]
+pub struct ChartSeries<'a> {
+    pub values: Range<'a>,
+    pub categories: Option<Range<'a>>,
+    pub name: Option<Expr<'a>>,
+}
+
+#[derive(Debug)]
+pub struct ChartOption<'a> {
+    pub kind: &'a str,
+    pub args: Vec<Expr<'a>>,
+}
+
+#[derive(Debug)]
+pub struct Chart<'a> {
+    pub kind: &'a str,
+    /// The top left cell of the chart, the cursor when not given.
+    pub position: Option<CellRef<'a>>,
+    pub series: Vec<ChartSeries<'a>>,
+    pub options: Vec<ChartOption<'a>>,
+}
+
+#[derive(Debug)]
+pub enum TotalsItem<'a> {
+    Function(&'a str),
+    Label(Expr<'a>),
+}
+
+#[derive(Debug)]
+pub struct Table<'a> {
+    pub name: Expr<'a>,
+    pub style: Option<Expr<'a>>,
+    /// The totals row, one item per column.
+    pub totals: Vec<TotalsItem<'a>>,
+    pub header: Row<'a>,
+    pub body: Vec<Element<'a>>,
+    /// The first and last row and column of the written table, including
+    /// the header and the totals row. Filled in by the VM.
+    pub region: Option<(u32, u16, u32, u16)>,
+}
+
+#[derive(Debug)]
+pub enum FilterTest<'a> {
+    Compare(CompareOp, Expr<'a>),
+    Text(&'a str, Expr<'a>),
+}
+
+#[derive(Debug)]
+pub enum FilterRule<'a> {
+    /// Show the rows with one of the values.
+    List(Vec<Expr<'a>>),
+    /// Show the rows passing both tests, or either of them with `or`.
+    Custom {
+        tests: Vec<FilterTest<'a>>,
+        or: bool,
+    },
+}
+
+#[derive(Debug)]
+pub struct FilterColumn<'a> {
+    /// The column within the filtered range, counted from 0.
+    pub column: u16,
+    pub rule: FilterRule<'a>,
+}
+
+#[derive(Debug)]
+pub struct AutoFilter<'a> {
+    pub range: Range<'a>,
+    pub columns: Vec<FilterColumn<'a>>,
+}
+
+/// A frame around a range, added to the formats of its edge cells.
+#[derive(Debug)]
+pub struct BorderRange<'a> {
+    pub range: Range<'a>,
+    pub style: Expr<'a>,
+    pub color: Option<Expr<'a>>,
+}
+
+/// A background color, added to the formats of the cells of a range.
+#[derive(Debug)]
+pub struct FillRange<'a> {
+    pub range: Range<'a>,
+    pub color: Expr<'a>,
+}
+
+#[derive(Debug)]
+pub struct DefinedName<'a> {
+    pub name: Expr<'a>,
+    pub range: Range<'a>,
+    /// Scoped to the sheet of the range instead of the workbook.
+    pub local: bool,
+}
+
+#[derive(Debug)]
+pub struct Protect<'a> {
+    pub password: Option<Expr<'a>>,
+    /// What users may still do on the protected sheet, Excel's defaults
+    /// when not given.
+    pub allow: Option<Vec<&'a str>>,
+}
+
+#[derive(Debug)]
+pub struct ProtectWorkbook<'a> {
+    pub password: Option<Expr<'a>>,
+}
+
+#[derive(Debug)]
+pub enum PageRule<'a> {
+    Range(&'a str, Range<'a>),
+    Setting(&'a str, Vec<Expr<'a>>),
+}
+
+#[derive(Debug)]
+pub struct PageSetup<'a> {
+    pub rules: Vec<PageRule<'a>>,
+}
+
+#[derive(Debug)]
+pub struct PageBreak {}
+
 #[derive(Debug)]
 pub struct Cr {}
 
@@ -82,7 +327,9 @@ pub struct Autofit {}
 #[derive(Debug)]
 pub enum Element<'a> {
     Format(Format<'a>),
-    Sheet(Sheet),
+    Properties(Properties<'a>),
+    Defaults(Defaults<'a>),
+    Sheet(Sheet<'a>),
     Anchor(Anchor<'a>),
     Row(Row<'a>),
     Mover(Move<'a>),
@@ -93,6 +340,18 @@ pub enum Element<'a> {
     Column(Column<'a>),
     RowSpec(RowSpec<'a>),
     ForEachHeader(ForEachHeader<'a>),
+    Conditional(Conditional<'a>),
+    Validation(Validation<'a>),
+    Chart(Chart<'a>),
+    Table(Table<'a>),
+    AutoFilter(AutoFilter<'a>),
+    DefinedName(DefinedName<'a>),
+    BorderRange(BorderRange<'a>),
+    FillRange(FillRange<'a>),
+    Protect(Protect<'a>),
+    ProtectWorkbook(ProtectWorkbook<'a>),
+    PageSetup(PageSetup<'a>),
+    PageBreak(PageBreak),
 }
 
 #[derive(Debug)]
@@ -120,6 +379,7 @@ pub enum Expr<'a> {
     Primary(Expression<'a>),
     Infix(Operator, Box<Expr<'a>>, Box<Expr<'a>>),
     Prefix(Operator, Box<Expr<'a>>),
+    Anchor(Box<Expr<'a>>, &'a str),
 }
 
 impl Default for Expr<'_> {
@@ -135,7 +395,7 @@ pub struct ForLoop<'a> {
     pub elements: Vec<Element<'a>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum CompareOp {
     Eq,
     Neq,
@@ -162,15 +422,21 @@ pub struct IfStatement<'a> {
 pub struct Column<'a> {
     pub start: u16,
     pub end: u16,
+    /// Empty when the width is left unchanged.
     pub unit: &'a str,
     pub width: f64,
+    pub format: Option<&'a str>,
+    pub hidden: bool,
 }
 
 #[derive(Debug)]
 pub struct RowSpec<'a> {
     pub start: u32,
+    /// Empty when the height is left unchanged.
     pub unit: &'a str,
     pub height: f64,
+    pub format: Option<&'a str>,
+    pub hidden: bool,
 }
 
 impl Expression<'_> {
@@ -197,6 +463,13 @@ impl Expression<'_> {
 }
 
 impl Expr<'_> {
+    pub fn as_value(&self) -> Option<&Value> {
+        match self {
+            Expr::Primary(Expression::Value(v)) => Some(v),
+            _ => None,
+        }
+    }
+
     pub fn as_str(&self) -> String {
         match self {
             Expr::Primary(v) => v.as_str(),
@@ -0,0 +1,371 @@
+use crate::engine::ast::{Cell, CellRef, Element, Range, Row, RowItem};
+use crate::engine::scope::Value;
+use ecow::EcoString;
+use indexmap::IndexMap;
+use std::sync::Arc;
+
+/// A cell together with the grid area it covers.
+#[derive(Debug)]
+pub struct PlacedCell<'c, 'a> {
+    pub row: u32,
+    pub col: u16,
+    pub end_row: u32,
+    pub end_col: u16,
+    pub cell: &'c Cell<'a>,
+}
+
+impl PlacedCell<'_, '_> {
+    /// Whether the cell spans more than one grid position.
+    pub fn is_merged(&self) -> bool {
+        self.end_row > self.row || self.end_col > self.col
+    }
+}
+
+/// A position remembered by `anchor()`.
+#[derive(Debug, Clone)]
+pub struct AnchorPosition {
+    /// The name of the sheet the anchor was set on.
+    pub sheet: EcoString,
+    pub row: u32,
+    pub col: u16,
+}
+
+/// The cursor and anchor state shared by the VM and every sheet processor.
+///
+/// The VM advances the layout after each element has been processed, so a
+/// processor always sees the position the element should be written at.
+#[derive(Debug, Default, Clone)]
+pub struct Layout {
+    /// The current row of the cursor.
+    pub row: u32,
+    /// The current column of the cursor.
+    pub col: u16,
+    /// The name of the current sheet.
+    pub sheet: EcoString,
+    /// The named anchors, keyed by their identifier (including the `@`).
+    pub anchors: IndexMap<EcoString, AnchorPosition>,
+}
+
+impl Layout {
+    /// Create a new layout with the cursor at the top left corner.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Look up the position of an anchor.
+    pub fn anchor(&self, name: &str) -> Option<(u32, u16)> {
+        self.anchors
+            .get(name)
+            .map(|anchor| (anchor.row, anchor.col))
+    }
+
+    /// Look up an anchor together with the sheet it was set on.
+    pub fn anchor_position(&self, name: &str) -> Option<&AnchorPosition> {
+        self.anchors.get(name)
+    }
+
+    /// The position of a cell given relative to an anchor.
+    pub fn cell_ref(&self, cell_ref: &CellRef) -> Option<(u32, u16)> {
+        let (row, col) = self.anchor(&cell_ref.anchor.as_str())?;
+        Some((
+            row.checked_add_signed(cell_ref.row)?,
+            col.checked_add_signed(cell_ref.col)?,
+        ))
+    }
+
+    /// The first and last row and column of a range, in this order.
+    pub fn range(&self, range: &Range) -> Option<(u32, u16, u32, u16)> {
+        let (first_row, first_col) = self.cell_ref(&range.from)?;
+        let (last_row, last_col) = match &range.to {
+            Some(to) => self.cell_ref(to)?,
+            None => (first_row, first_col),
+        };
+        Some((
+            first_row.min(last_row),
+            first_col.min(last_col),
+            first_row.max(last_row),
+            first_col.max(last_col),
+        ))
+    }
+
+    /// The cursor as a value, available as `$cursor` in expressions.
+    pub fn cursor_value(&self) -> Value {
+        position_value(self.row, self.col)
+    }
+
+    /// Places the cells of a row side by side, starting at the cursor.
+    ///
+    /// Every cell takes `colspan` columns and `rowspan` rows; the cursor
+    /// itself is only moved by [`Layout::advance`].
+    pub fn place<'c, 'a>(&self, row: &'c Row<'a>) -> Vec<PlacedCell<'c, 'a>> {
+        let mut placed = Vec::with_capacity(row.cells.len());
+        let mut col = self.col;
+        for item in &row.cells {
+            let cell = match item {
+                RowItem::Cell(cell) => cell,
+                RowItem::ForEachCell(_) => continue,
+                RowItem::ForEachHeader(_) => continue,
+            };
+            let colspan = cell.colspan.max(1);
+            let rowspan = cell.rowspan.max(1);
+            placed.push(PlacedCell {
+                row: self.row,
+                col,
+                end_row: self.row + rowspan as u32 - 1,
+                end_col: col + colspan - 1,
+                cell,
+            });
+            col += colspan;
+        }
+        placed
+    }
+
+    /// Move the cursor past an element that has just been processed.
+    pub fn advance(&mut self, item: &Element) {
+        match item {
+            Element::Sheet(sheet) => {
+                self.sheet = EcoString::from(sheet.name.as_str());
+                self.row = 0;
+                self.col = 0;
+            }
+            Element::Row(_) => {
+                self.row += 1;
+            }
+            Element::Anchor(anchor) => {
+                let name = anchor.name.as_str();
+                self.anchors.insert(
+                    EcoString::from(name),
+                    AnchorPosition {
+                        sheet: self.sheet.clone(),
+                        row: self.row,
+                        col: self.col,
+                    },
+                );
+            }
+            Element::Mover(mover) => {
+                if let Some(anchor) = mover.anchor {
+                    if let Some((a_row, a_col)) = self.anchor(anchor) {
+                        self.row = a_row.checked_add_signed(mover.row).unwrap_or_default();
+                        self.col = a_col.checked_add_signed(mover.col).unwrap_or_default();
+                    }
+                } else {
+                    self.row = self.row.checked_add_signed(mover.row).unwrap_or_default();
+                    self.col = self.col.checked_add_signed(mover.col).unwrap_or_default();
+                }
+            }
+            Element::Table(table) => {
+                if let Some((_, _, last_row, _)) = table.region {
+                    self.row = last_row + 1;
+                }
+            }
+            Element::Cr(_) => {
+                self.row += 1;
+                self.col = 0;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Builds a `{ row, col }` object value for a grid position.
+pub fn position_value(row: u32, col: u16) -> Value {
+    let mut map = IndexMap::new();
+    map.insert(EcoString::from("row"), Value::Integer(row as i64));
+    map.insert(EcoString::from("col"), Value::Integer(col as i64));
+    Value::Object(Arc::new(map))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::ast::{Expr, Expression};
+    use crate::engine::diag::SpreadSheetError;
+    use crate::engine::parser::parse_stream;
+    use crate::engine::vm::{SheetProcessor, VM};
+
+    /// A placed cell: the sheet, the first and last row and column, the text.
+    type Placement = (EcoString, u32, u16, u32, u16, String);
+
+    /// Records where every cell is placed.
+    #[derive(Default)]
+    struct Placements(Vec<Placement>);
+
+    impl SheetProcessor for Placements {
+        fn process(&mut self, item: &Element, layout: &Layout) -> Result<(), SpreadSheetError> {
+            if let Element::Row(row) = item {
+                for placed in layout.place(row) {
+                    self.0.push((
+                        layout.sheet.clone(),
+                        placed.row,
+                        placed.col,
+                        placed.end_row,
+                        placed.end_col,
+                        placed.cell.value.as_str(),
+                    ));
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn run(template: &str) -> (Layout, Vec<Placement>) {
+        let tree = parse_stream(template).unwrap();
+        let mut vm = VM::default();
+        let items = (1..=3).map(Value::Integer).collect();
+        vm.scopes.top.define("items", Value::Array(Arc::new(items)));
+        let mut placements = Placements::default();
+        vm.run(&tree.elements, &mut placements).unwrap();
+        (vm.layout, placements.0)
+    }
+
+    /// The cells placed on a sheet, as `(row, col, end_row, end_col, text)`.
+    fn cells(template: &str) -> Vec<(u32, u16, u32, u16, String)> {
+        run(template)
+            .1
+            .into_iter()
+            .map(|(_, row, col, end_row, end_col, text)| (row, col, end_row, end_col, text))
+            .collect()
+    }
+
+    fn cell_ref(anchor: &str, row: i32, col: i16) -> CellRef<'static> {
+        CellRef {
+            anchor: Expr::Primary(Expression::Value(Value::String(anchor.to_string()))),
+            row,
+            col,
+        }
+    }
+
+    fn text(s: &str) -> String {
+        s.to_string()
+    }
+
+    #[test]
+    fn cells_take_their_spans() {
+        let placed = cells(
+            r#"
+sheet("S")
+[ str("a", colspan(2)), str("b"), str("c", rowspan(3)), str("d", colspan(2), rowspan(2)) ]
+[ str("e") ]
+"#,
+        );
+        assert_eq!(
+            placed,
+            [
+                (0, 0, 0, 1, text("a")),
+                (0, 2, 0, 2, text("b")),
+                (0, 3, 2, 3, text("c")),
+                (0, 4, 1, 5, text("d")),
+                (1, 0, 1, 0, text("e")),
+            ]
+        );
+    }
+
+    #[test]
+    fn move_and_cr_set_the_cursor() {
+        let placed = cells(
+            r#"
+sheet("S")
+anchor(@top)
+[ str("a") ]
+move(1, 2)
+[ str("b") ]
+cr
+[ str("c") ]
+move(@top, 0, 5)
+[ str("d") ]
+move(-1, -5)
+[ str("e") ]
+"#,
+        );
+        assert_eq!(
+            placed,
+            [
+                (0, 0, 0, 0, text("a")),
+                (2, 2, 2, 2, text("b")),
+                (4, 0, 4, 0, text("c")),
+                (0, 5, 0, 5, text("d")),
+                (0, 0, 0, 0, text("e")),
+            ]
+        );
+    }
+
+    #[test]
+    fn sheets_restart_at_the_top_left() {
+        let (layout, placed) = run(r#"
+sheet("A")
+move(2, 3)
+anchor(@a)
+[ str("a") ]
+sheet("B")
+[ str("b") ]
+"#);
+        assert_eq!(placed[0].0, "A");
+        assert_eq!((placed[0].1, placed[0].2), (2, 3));
+        assert_eq!(placed[1].0, "B");
+        assert_eq!((placed[1].1, placed[1].2), (0, 0));
+        let anchor = layout.anchor_position("@a").unwrap();
+        assert_eq!((anchor.sheet.as_str(), anchor.row, anchor.col), ("A", 2, 3));
+        assert_eq!((layout.sheet.as_str(), layout.row, layout.col), ("B", 1, 0));
+    }
+
+    #[test]
+    fn anchor_names_may_be_computed() {
+        let (layout, _) = run(r#"
+sheet("S")
+for $x in $items {
+  anchor(@section_ + $index)
+  [ str("x") ]
+  cr
+}
+"#);
+        assert_eq!(layout.anchor("@section_0"), Some((0, 0)));
+        assert_eq!(layout.anchor("@section_1"), Some((2, 0)));
+        assert_eq!(layout.anchor("@section_2"), Some((4, 0)));
+    }
+
+    #[test]
+    fn expressions_read_the_cursor_and_the_anchors() {
+        let placed = cells(
+            r#"
+sheet("S")
+move(2, 1)
+anchor(@top)
+[ num($cursor.row), num($cursor.col) ]
+for $x in $items {
+  anchor(@item_ + $index)
+  [ num(0) ]
+}
+[ num(anchor(@top).row), num(anchor(@top).col), num(anchor(@item_ + 1).row) ]
+"#,
+        );
+        let texts: Vec<&str> = placed.iter().map(|cell| cell.4.as_str()).collect();
+        assert_eq!(texts, ["2", "1", "0", "0", "0", "2", "1", "4"]);
+    }
+
+    #[test]
+    fn cell_refs_and_ranges_are_relative_to_anchors() {
+        let (layout, _) = run(r#"
+sheet("S")
+move(3, 2)
+anchor(@a)
+move(5, 1)
+anchor(@b)
+"#);
+        assert_eq!(layout.cell_ref(&cell_ref("@a", 1, 2)), Some((4, 4)));
+        assert_eq!(layout.cell_ref(&cell_ref("@a", -3, -2)), Some((0, 0)));
+        assert_eq!(layout.cell_ref(&cell_ref("@a", -4, 0)), None);
+        assert_eq!(layout.cell_ref(&cell_ref("@a", 0, -3)), None);
+        assert_eq!(layout.cell_ref(&cell_ref("@missing", 0, 0)), None);
+
+        let range = Range {
+            from: cell_ref("@b", 0, 1),
+            to: Some(cell_ref("@a", 0, 0)),
+        };
+        assert_eq!(layout.range(&range), Some((3, 2, 8, 4)));
+        let single = Range {
+            from: cell_ref("@b", -1, 0),
+            to: None,
+        };
+        assert_eq!(layout.range(&single), Some((7, 3, 7, 3)));
+    }
+}
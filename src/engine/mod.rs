@@ -1,5 +1,6 @@
 pub mod ast;
 pub mod diag;
+pub mod layout;
 pub mod parser;
 pub mod scope;
 pub mod vm;
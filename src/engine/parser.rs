@@ -41,6 +41,35 @@ fn parse_element(pair: Pair<Rule>) -> Option<Element> {
             let format = parse_format(pair.into_inner());
             Some(Element::Format(format))
         }
+        Rule::workbook_properties => {
+            let mut properties = Vec::new();
+            for pair in pair.into_inner() {
+                let mut pairs = pair.into_inner();
+                let kind = pairs
+                    .next()
+                    .expect("grammar should guarantee pair")
+                    .as_str();
+                let args = parse_exprs(pairs);
+                properties.push(Property { kind, args });
+            }
+            Some(Element::Properties(Properties { properties }))
+        }
+        Rule::defaults => {
+            let mut formats = Vec::new();
+            for pair in pair.into_inner() {
+                let mut pairs = pair.into_inner();
+                let kind = pairs
+                    .next()
+                    .expect("grammar should guarantee pair")
+                    .as_str();
+                let format = pairs
+                    .next()
+                    .expect("grammar should guarantee pair")
+                    .as_str();
+                formats.push(DefaultFormat { kind, format });
+            }
+            Some(Element::Defaults(Defaults { formats }))
+        }
         Rule::sheet => {
             let sheet = parse_sheet(pair.into_inner());
             Some(Element::Sheet(sheet))
@@ -79,6 +108,102 @@ fn parse_element(pair: Pair<Rule>) -> Option<Element> {
             let header = parse_for_each_header(pair.into_inner());
             Some(Element::ForEachHeader(header))
         }
+        Rule::conditional => {
+            let conditional = parse_conditional(pair.into_inner());
+            Some(Element::Conditional(conditional))
+        }
+        Rule::validation => {
+            let validation = parse_validation(pair.into_inner());
+            Some(Element::Validation(validation))
+        }
+        Rule::chart => {
+            let chart = parse_chart(pair.into_inner());
+            Some(Element::Chart(chart))
+        }
+        Rule::table => {
+            let table = parse_table(pair.into_inner());
+            Some(Element::Table(table))
+        }
+        Rule::autofilter => {
+            let autofilter = parse_autofilter(pair.into_inner());
+            Some(Element::AutoFilter(autofilter))
+        }
+        Rule::defined_name => {
+            let mut local = false;
+            let mut name = Expr::default();
+            let mut range = None;
+            for pair in pair.into_inner() {
+                match pair.as_rule() {
+                    Rule::name_scope => local = pair.as_str() == "local_name",
+                    Rule::expr => name = parse_expr(pair.into_inner(), &make_pratt()),
+                    Rule::range => range = Some(parse_range(pair.into_inner())),
+                    _ => {}
+                }
+            }
+            Some(Element::DefinedName(DefinedName {
+                name,
+                range: range.expect("grammar should guarantee a range"),
+                local,
+            }))
+        }
+        Rule::border_range => {
+            let mut range = None;
+            let mut args = Vec::new();
+            for pair in pair.into_inner() {
+                match pair.as_rule() {
+                    Rule::range_bounds => range = Some(parse_range(pair.into_inner())),
+                    Rule::expr => args.push(parse_expr(pair.into_inner(), &make_pratt())),
+                    _ => {}
+                }
+            }
+            let mut args = args.into_iter();
+            Some(Element::BorderRange(BorderRange {
+                range: range.expect("grammar should guarantee a range"),
+                style: args.next().expect("grammar should guarantee a style"),
+                color: args.next(),
+            }))
+        }
+        Rule::fill_range => {
+            let mut range = None;
+            let mut color = Expr::default();
+            for pair in pair.into_inner() {
+                match pair.as_rule() {
+                    Rule::range_bounds => range = Some(parse_range(pair.into_inner())),
+                    Rule::expr => color = parse_expr(pair.into_inner(), &make_pratt()),
+                    _ => {}
+                }
+            }
+            Some(Element::FillRange(FillRange {
+                range: range.expect("grammar should guarantee a range"),
+                color,
+            }))
+        }
+        Rule::protect => {
+            let mut password = None;
+            let mut allow = None;
+            for pair in pair.into_inner() {
+                match pair.as_rule() {
+                    Rule::protect_password => password = parse_exprs(pair.into_inner()).pop(),
+                    Rule::protect_allow => {
+                        allow = Some(pair.into_inner().map(|option| option.as_str()).collect())
+                    }
+                    _ => {}
+                }
+            }
+            Some(Element::Protect(Protect { password, allow }))
+        }
+        Rule::protect_workbook => {
+            let password = pair
+                .into_inner()
+                .next()
+                .and_then(|password| parse_exprs(password.into_inner()).pop());
+            Some(Element::ProtectWorkbook(ProtectWorkbook { password }))
+        }
+        Rule::page_setup => {
+            let page_setup = parse_page_setup(pair.into_inner());
+            Some(Element::PageSetup(page_setup))
+        }
+        Rule::page_break => Some(Element::PageBreak(PageBreak {})),
         _ => None,
     }
 }
@@ -159,15 +284,7 @@ fn parse_condition(pairs: pest::iterators::Pairs<Rule>) -> Condition {
 
     let op = if let Some(op_pair) = pairs.next() {
         if op_pair.as_rule() == Rule::compare_op {
-            let cmp = match op_pair.as_str() {
-                "==" => CompareOp::Eq,
-                "!=" => CompareOp::Neq,
-                "<=" => CompareOp::Lte,
-                ">=" => CompareOp::Gte,
-                "<" => CompareOp::Lt,
-                ">" => CompareOp::Gt,
-                _ => unreachable!(),
-            };
+            let cmp = parse_compare_op(op_pair.as_str());
             let rhs_pair = pairs
                 .next()
                 .expect("grammar should guarantee pair after op");
@@ -183,6 +300,18 @@ fn parse_condition(pairs: pest::iterators::Pairs<Rule>) -> Condition {
     Condition { lhs, op }
 }
 
+fn parse_compare_op(op: &str) -> CompareOp {
+    match op {
+        "==" => CompareOp::Eq,
+        "!=" => CompareOp::Neq,
+        "<=" => CompareOp::Lte,
+        ">=" => CompareOp::Gte,
+        "<" => CompareOp::Lt,
+        ">" => CompareOp::Gt,
+        _ => unreachable!(),
+    }
+}
+
 fn make_pratt() -> PrattParser<Rule> {
     PrattParser::new()
         .op(Op::infix(Rule::add, Assoc::Left) | Op::infix(Rule::sub, Assoc::Left))
@@ -228,22 +357,55 @@ fn parse_format(pairs: pest::iterators::Pairs<Rule>) -> Format {
 
 fn parse_sheet(pairs: pest::iterators::Pairs<Rule>) -> Sheet {
     let mut name = String::from("");
+    let mut options = Vec::new();
     for pair in pairs {
-        if pair.as_rule() == Rule::sheet_identifier {
-            name = decode_string(pair.as_str());
+        match pair.as_rule() {
+            Rule::sheet_identifier => name = decode_string(pair.as_str()),
+            Rule::sheet_option => {
+                let mut pairs = pair.into_inner();
+                let first = pairs.next().expect("grammar should guarantee pair");
+                options.push(match first.as_rule() {
+                    Rule::sheet_freeze => SheetOption::Freeze(parse_cell_ref(
+                        first
+                            .into_inner()
+                            .next()
+                            .expect("grammar should guarantee a cell reference")
+                            .into_inner(),
+                    )),
+                    _ => SheetOption::Setting(first.as_str(), parse_exprs(pairs).pop()),
+                });
+            }
+            _ => {}
         }
     }
-    Sheet { name }
+    Sheet { name, options }
 }
 
 fn parse_anchor(pairs: pest::iterators::Pairs<Rule>) -> Anchor {
-    let mut identifier = "";
+    let mut name = Expr::default();
     for pair in pairs {
-        if pair.as_rule() == Rule::anchor_identifier {
-            identifier = pair.as_str();
+        if pair.as_rule() == Rule::expr {
+            name = parse_expr(pair.into_inner(), &make_pratt());
         }
     }
-    Anchor { identifier }
+    Anchor { name }
+}
+
+fn parse_anchor_ref(pairs: pest::iterators::Pairs<Rule>) -> Expr {
+    let mut name = Expr::default();
+    let mut field = "";
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::expr => {
+                name = parse_expr(pair.into_inner(), &make_pratt());
+            }
+            Rule::anchor_field => {
+                field = pair.as_str();
+            }
+            _ => {}
+        }
+    }
+    Expr::Anchor(Box::new(name), field)
 }
 
 fn decode_string(s: &str) -> String {
@@ -299,6 +461,9 @@ fn parse_value(pair: Pair<Rule>) -> Value {
         Rule::string => {
             value = Value::String(decode_string(pair.as_str()));
         }
+        Rule::boolean => {
+            value = Value::Boolean(pair.as_str() == "true");
+        }
         _ => {}
     }
 
@@ -331,6 +496,8 @@ fn parse_column(pairs: pest::iterators::Pairs<Rule>) -> Column {
     let mut start = 0;
     let mut end = 0;
     let mut width = 0.0;
+    let mut format = None;
+    let mut hidden = false;
     let mut number_idx = 0;
     for pair in pairs {
         match pair.as_rule() {
@@ -349,6 +516,8 @@ fn parse_column(pairs: pest::iterators::Pairs<Rule>) -> Column {
             Rule::width_unit => {
                 unit = pair.as_str();
             }
+            Rule::format_identifier => format = Some(pair.as_str()),
+            Rule::line_hidden => hidden = true,
             _ => {}
         }
     }
@@ -357,6 +526,8 @@ fn parse_column(pairs: pest::iterators::Pairs<Rule>) -> Column {
         end,
         unit,
         width,
+        format,
+        hidden,
     }
 }
 
@@ -364,6 +535,8 @@ fn parse_rowspec(pairs: pest::iterators::Pairs<Rule>) -> RowSpec {
     let mut unit = "";
     let mut start = 0;
     let mut height = 0.0;
+    let mut format = None;
+    let mut hidden = false;
     let mut number_idx = 0;
     for pair in pairs {
         match pair.as_rule() {
@@ -379,6 +552,8 @@ fn parse_rowspec(pairs: pest::iterators::Pairs<Rule>) -> RowSpec {
             Rule::width_unit => {
                 unit = pair.as_str();
             }
+            Rule::format_identifier => format = Some(pair.as_str()),
+            Rule::line_hidden => hidden = true,
             _ => {}
         }
     }
@@ -386,16 +561,23 @@ fn parse_rowspec(pairs: pest::iterators::Pairs<Rule>) -> RowSpec {
         start,
         unit,
         height,
+        format,
+        hidden,
     }
 }
 
 fn parse_cell(pairs: pest::iterators::Pairs<Rule>) -> Cell {
     let mut value = Expr::Primary(Expression::Value(Value::Integer(0)));
+    let mut text = None;
+    let mut rich = Vec::new();
     let mut format = None;
     let mut cell_type = CellType::Str;
     let mut colspan = 1;
     let mut rowspan = 1;
     let mut image_mode = None;
+    let mut image_options = Vec::new();
+    let mut note = None;
+    let mut value_seen = false;
     for pair in pairs {
         match pair.as_rule() {
             Rule::cell_type => {
@@ -405,6 +587,8 @@ fn parse_cell(pairs: pest::iterators::Pairs<Rule>) -> Cell {
                     "date" => CellType::Date,
                     "img" => CellType::Image,
                     "bool" => CellType::Bool,
+                    "url" => CellType::Url,
+                    "rich" => CellType::Rich,
                     _ => CellType::Str,
                 };
             }
@@ -414,13 +598,51 @@ fn parse_cell(pairs: pest::iterators::Pairs<Rule>) -> Cell {
             Rule::expression => {
                 value = Expr::Primary(parse_expression(pair.into_inner()));
             }
+            Rule::expr if text.is_none() && value_seen => {
+                text = Some(parse_expr(pair.into_inner(), &make_pratt()));
+            }
             Rule::expr => {
                 value = parse_expr(pair.into_inner(), &make_pratt());
+                value_seen = true;
                 // println!("{:?}", value);
             }
             Rule::image_mode => {
                 image_mode = Some(pair.as_str());
             }
+            Rule::image_options => {
+                for option in pair.into_inner() {
+                    let mut pairs = option.into_inner();
+                    let kind = pairs
+                        .next()
+                        .expect("grammar should guarantee pair")
+                        .as_str();
+                    let value = parse_expr(
+                        pairs
+                            .next()
+                            .expect("grammar should guarantee pair")
+                            .into_inner(),
+                        &make_pratt(),
+                    );
+                    image_options.push(ImageOption { kind, value });
+                }
+            }
+            Rule::note => {
+                note = Some(parse_note(pair.into_inner()));
+            }
+            Rule::rich_text => {
+                for segment in pair.into_inner() {
+                    let mut text = Expr::default();
+                    let mut format = None;
+                    for pair in segment.into_inner() {
+                        match pair.as_rule() {
+                            Rule::expr => text = parse_expr(pair.into_inner(), &make_pratt()),
+                            Rule::format_identifier => format = Some(pair.as_str()),
+                            _ => {}
+                        }
+                    }
+                    rich.push(RichSegment { text, format });
+                }
+            }
             Rule::colspan => {
                 let pairs = pair.into_inner();
                 for pair in pairs {
@@ -444,21 +666,47 @@ fn parse_cell(pairs: pest::iterators::Pairs<Rule>) -> Cell {
     Cell {
         cell_type,
         value,
+        text,
+        rich,
         format,
         colspan,
         rowspan,
         image_mode,
+        image_options,
+        note,
+    }
+}
+
+fn parse_note(pairs: pest::iterators::Pairs<Rule>) -> Note {
+    let mut text = Expr::default();
+    let mut author = None;
+    let mut visible = false;
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::expr => text = parse_expr(pair.into_inner(), &make_pratt()),
+            Rule::note_author => author = parse_exprs(pair.into_inner()).pop(),
+            Rule::note_visible => visible = pair.into_inner().as_str() == "true",
+            _ => {}
+        }
+    }
+    Note {
+        text,
+        author,
+        visible,
     }
 }
 
 fn parse_expression(pairs: pest::iterators::Pairs<Rule>) -> Expression {
     for pair in pairs {
         match pair.as_rule() {
-            Rule::number | Rule::string => {
+            Rule::number | Rule::string | Rule::boolean => {
                 let value = parse_value(pair);
                 return Expression::Value(value);
             }
             Rule::variable_identifier => return Expression::Identifier(pair.as_str()),
+            Rule::anchor_identifier => {
+                return Expression::Value(Value::String(pair.as_str().to_string()))
+            }
             _ => {}
         }
     }
@@ -471,6 +719,7 @@ fn parse_expr<'a>(pairs: pest::iterators::Pairs<'a, Rule>, pratt: &PrattParser<R
         .map_primary(|primary| match primary.as_rule() {
             Rule::expression => Expr::Primary(parse_expression(primary.into_inner())),
             Rule::expr => parse_expr(primary.into_inner(), pratt), // from "(" ~ expr ~ ")"
+            Rule::anchor_ref => parse_anchor_ref(primary.into_inner()),
             _ => unreachable!(),
         })
         .map_prefix(|op, rhs| match op.as_rule() {
@@ -514,10 +763,14 @@ fn parse_for_each_cell(pairs: pest::iterators::Pairs<Rule>) -> ForEachCell {
     let mut cell = Cell {
         cell_type: CellType::Str,
         value: Expr::Primary(Expression::Value(Value::Integer(0))),
+        text: None,
+        rich: Vec::new(),
         format: None,
         colspan: 1,
         rowspan: 1,
         image_mode: None,
+        image_options: Vec::new(),
+        note: None,
     };
 
     for pair in pairs {
@@ -554,3 +807,322 @@ fn parse_for_each_header(pairs: pest::iterators::Pairs<Rule>) -> ForEachHeader {
     }
     ForEachHeader { variable, format }
 }
+
+fn parse_cell_ref(pairs: pest::iterators::Pairs<Rule>) -> CellRef {
+    let mut anchor = Expr::default();
+    let mut row = 0;
+    let mut col = 0;
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::expr => anchor = parse_expr(pair.into_inner(), &make_pratt()),
+            Rule::ref_row => row = pair.as_str().parse::<i32>().unwrap_or_default(),
+            Rule::ref_col => col = pair.as_str().parse::<i16>().unwrap_or_default(),
+            _ => {}
+        }
+    }
+    CellRef { anchor, row, col }
+}
+
+fn parse_range(pairs: pest::iterators::Pairs<Rule>) -> Range {
+    let mut refs = pairs
+        .filter(|pair| pair.as_rule() == Rule::cell_ref)
+        .map(|pair| parse_cell_ref(pair.into_inner()));
+    let from = refs
+        .next()
+        .expect("grammar should guarantee a cell reference");
+    let to = refs.next();
+    Range { from, to }
+}
+
+fn parse_exprs(pairs: pest::iterators::Pairs<Rule>) -> Vec<Expr> {
+    pairs
+        .filter(|pair| pair.as_rule() == Rule::expr)
+        .map(|pair| parse_expr(pair.into_inner(), &make_pratt()))
+        .collect()
+}
+
+fn parse_condition_test(pair: Pair<Rule>) -> ConditionTest {
+    let rule = pair.as_rule();
+    let mut pairs = pair.into_inner();
+    match rule {
+        Rule::cond_cell => {
+            let first = pairs.next().expect("grammar should guarantee pair");
+            if first.as_rule() == Rule::cond_between {
+                let negated = first
+                    .clone()
+                    .into_inner()
+                    .any(|pair| pair.as_rule() == Rule::not_between);
+                let mut bounds = parse_exprs(first.into_inner()).into_iter();
+                let low = bounds.next().unwrap_or_default();
+                let high = bounds.next().unwrap_or_default();
+                ConditionTest::Between { negated, low, high }
+            } else {
+                let op = parse_compare_op(first.as_str());
+                let value = parse_exprs(pairs).pop().unwrap_or_default();
+                ConditionTest::Cell(op, value)
+            }
+        }
+        Rule::cond_text => {
+            let op = pairs
+                .next()
+                .expect("grammar should guarantee pair")
+                .as_str();
+            let value = parse_exprs(pairs).pop().unwrap_or_default();
+            ConditionTest::Text(op, value)
+        }
+        Rule::cond_top => {
+            let mut bottom = false;
+            let mut rank = 10;
+            let mut percent = false;
+            for pair in pairs {
+                match pair.as_rule() {
+                    Rule::top_kind => bottom = pair.as_str() == "bottom",
+                    Rule::number => rank = pair.as_str().parse().unwrap_or(10),
+                    Rule::percent => percent = true,
+                    _ => {}
+                }
+            }
+            ConditionTest::Top {
+                bottom,
+                rank,
+                percent,
+            }
+        }
+        _ => {
+            let unique = pairs.any(|pair| pair.as_str() == "unique");
+            ConditionTest::Duplicate { unique }
+        }
+    }
+}
+
+fn parse_conditional(pairs: pest::iterators::Pairs<Rule>) -> Conditional {
+    let mut range = None;
+    let mut rules = Vec::new();
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::range => range = Some(parse_range(pair.into_inner())),
+            Rule::cond_when => {
+                let mut test = None;
+                let mut format = "";
+                for pair in pair.into_inner() {
+                    match pair.as_rule() {
+                        Rule::format_identifier => format = pair.as_str(),
+                        _ => test = Some(parse_condition_test(pair)),
+                    }
+                }
+                let test = test.expect("grammar should guarantee a condition");
+                rules.push(ConditionalRule::When(test, format));
+            }
+            Rule::cond_data_bar => {
+                rules.push(ConditionalRule::DataBar(
+                    parse_exprs(pair.into_inner()).pop(),
+                ));
+            }
+            Rule::cond_color_scale => {
+                let mut pairs = pair.into_inner();
+                let kind = pairs
+                    .next()
+                    .expect("grammar should guarantee pair")
+                    .as_str();
+                let colors = parse_exprs(pairs);
+                if kind == "color_scale_2" {
+                    rules.push(ConditionalRule::ColorScale2(colors));
+                } else {
+                    rules.push(ConditionalRule::ColorScale3(colors));
+                }
+            }
+            Rule::cond_icon_set => {
+                let icons = parse_exprs(pair.into_inner()).pop().unwrap_or_default();
+                rules.push(ConditionalRule::IconSet(icons));
+            }
+            _ => {}
+        }
+    }
+    Conditional {
+        range: range.expect("grammar should guarantee a range"),
+        rules,
+    }
+}
+
+fn parse_validation(pairs: pest::iterators::Pairs<Rule>) -> Validation {
+    let mut range = None;
+    let mut rules = Vec::new();
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::range => range = Some(parse_range(pair.into_inner())),
+            Rule::validation_rule => {
+                let mut pairs = pair.into_inner();
+                let kind = pairs
+                    .next()
+                    .expect("grammar should guarantee pair")
+                    .as_str();
+                let args = parse_exprs(pairs);
+                rules.push(ValidationRule { kind, args });
+            }
+            _ => {}
+        }
+    }
+    Validation {
+        range: range.expect("grammar should guarantee a range"),
+        rules,
+    }
+}
+
+fn parse_autofilter(pairs: pest::iterators::Pairs<Rule>) -> AutoFilter {
+    let mut range = None;
+    let mut columns = Vec::new();
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::range => range = Some(parse_range(pair.into_inner())),
+            Rule::filter_rule => {
+                let mut column = 0;
+                let mut list = None;
+                let mut tests = Vec::new();
+                let mut or = false;
+                for pair in pair.into_inner() {
+                    match pair.as_rule() {
+                        Rule::number => column = pair.as_str().parse().unwrap_or(0),
+                        Rule::filter_in => list = Some(parse_exprs(pair.into_inner())),
+                        Rule::filter_join => or = pair.as_str() == "or",
+                        Rule::filter_test => {
+                            let mut pairs = pair.into_inner();
+                            let op = pairs.next().expect("grammar should guarantee pair");
+                            let value = parse_exprs(pairs).pop().unwrap_or_default();
+                            tests.push(match op.as_rule() {
+                                Rule::compare_op => {
+                                    FilterTest::Compare(parse_compare_op(op.as_str()), value)
+                                }
+                                _ => FilterTest::Text(op.as_str(), value),
+                            });
+                        }
+                        _ => {}
+                    }
+                }
+                let rule = match list {
+                    Some(values) => FilterRule::List(values),
+                    None => FilterRule::Custom { tests, or },
+                };
+                columns.push(FilterColumn { column, rule });
+            }
+            _ => {}
+        }
+    }
+    AutoFilter {
+        range: range.expect("grammar should guarantee a range"),
+        columns,
+    }
+}
+
+fn parse_page_setup(pairs: pest::iterators::Pairs<Rule>) -> PageSetup {
+    let mut rules = Vec::new();
+    for pair in pairs {
+        let mut pairs = pair.into_inner();
+        let kind = pairs.next().expect("grammar should guarantee pair");
+        rules.push(match kind.as_rule() {
+            Rule::page_range_kind => PageRule::Range(
+                kind.as_str(),
+                parse_range(
+                    pairs
+                        .next()
+                        .expect("grammar should guarantee a range")
+                        .into_inner(),
+                ),
+            ),
+            _ => PageRule::Setting(kind.as_str(), parse_exprs(pairs)),
+        });
+    }
+    PageSetup { rules }
+}
+
+fn parse_table(pairs: pest::iterators::Pairs<Rule>) -> Table {
+    let mut exprs = Vec::new();
+    let mut totals = Vec::new();
+    let mut header = None;
+    let mut body = Vec::new();
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::expr => exprs.push(parse_expr(pair.into_inner(), &make_pratt())),
+            Rule::table_totals => {
+                for item in pair.into_inner() {
+                    let item = item
+                        .into_inner()
+                        .next()
+                        .expect("grammar should guarantee pair");
+                    totals.push(match item.as_rule() {
+                        Rule::totals_function => TotalsItem::Function(item.as_str()),
+                        _ => TotalsItem::Label(parse_expr(item.into_inner(), &make_pratt())),
+                    });
+                }
+            }
+            Rule::row if header.is_none() => header = Some(parse_row(pair.into_inner())),
+            _ => {
+                if let Some(element) = parse_element(pair) {
+                    body.push(element);
+                }
+            }
+        }
+    }
+    let mut exprs = exprs.into_iter();
+    Table {
+        name: exprs.next().expect("grammar should guarantee a name"),
+        style: exprs.next(),
+        totals,
+        header: header.expect("grammar should guarantee a header row"),
+        body,
+        region: None,
+    }
+}
+
+fn parse_chart(pairs: pest::iterators::Pairs<Rule>) -> Chart {
+    let mut kind = "";
+    let mut position = None;
+    let mut series = Vec::new();
+    let mut options = Vec::new();
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::chart_kind => kind = pair.as_str(),
+            Rule::cell_ref => position = Some(parse_cell_ref(pair.into_inner())),
+            Rule::chart_series => {
+                let mut values = None;
+                let mut categories = None;
+                let mut name = None;
+                for pair in pair.into_inner() {
+                    match pair.as_rule() {
+                        Rule::range => values = Some(parse_range(pair.into_inner())),
+                        Rule::series_categories => {
+                            categories = pair
+                                .into_inner()
+                                .next()
+                                .map(|range| parse_range(range.into_inner()));
+                        }
+                        Rule::series_name => {
+                            name = parse_exprs(pair.into_inner()).pop();
+                        }
+                        _ => {}
+                    }
+                }
+                series.push(ChartSeries {
+                    values: values.expect("grammar should guarantee a range"),
+                    categories,
+                    name,
+                });
+            }
+            Rule::chart_option => {
+                let mut pairs = pair.into_inner();
+                let kind = pairs
+                    .next()
+                    .expect("grammar should guarantee pair")
+                    .as_str();
+                let args = parse_exprs(pairs);
+                options.push(ChartOption { kind, args });
+            }
+            _ => {}
+        }
+    }
+    Chart {
+        kind,
+        position,
+        series,
+        options,
+    }
+}
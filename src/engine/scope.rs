@@ -42,31 +42,14 @@ impl Value {
     pub fn as_bool(&self) -> bool {
         match self {
             Value::Boolean(b) => *b,
-            Value::Integer(i) => {
-                if *i == 0 {
-                    false
-                } else {
-                    true
-                }
-            }
-            Value::Float(f) => {
-                if *f == 0.0 {
-                    false
-                } else {
-                    true
-                }
-            }
-            Value::String(s) => {
-                if s.to_lowercase() == "true" {
-                    true
-                } else {
-                    false
-                }
-            }
+            Value::Integer(i) => *i != 0,
+            Value::Float(f) => *f != 0.0,
+            Value::String(s) => s.to_lowercase() == "true",
             _ => false,
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn eq(&self, rhs: &Value) -> bool {
         match (self, rhs) {
             (Value::Integer(l), Value::Integer(r)) => l == r,
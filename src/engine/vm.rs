@@ -1,22 +1,39 @@
 use crate::engine::ast::{
-    Cell, CompareOp, Condition, Element, Expr, Expression, ForEachHeader, ForLoop, Format,
-    IfStatement, Modifier, Operator, Row, RowItem,
+    Anchor, AutoFilter, BorderRange, Cell, CellRef, CellType, Chart, ChartOption, ChartSeries,
+    CompareOp, Condition, ConditionTest, Conditional, ConditionalRule, DefinedName, Element, Expr,
+    Expression, FillRange, FilterColumn, FilterRule, FilterTest, ForEachHeader, ForLoop, Format,
+    IfStatement, ImageOption, Modifier, Note, Operator, PageRule, PageSetup, Properties, Property,
+    Protect, ProtectWorkbook, Range, RichSegment, Row, RowItem, Sheet, SheetOption, Table,
+    TotalsItem, Validation, ValidationRule,
 };
 use crate::engine::diag::SpreadSheetError;
-use crate::engine::scope::{Scopes, Value};
+use crate::engine::layout::Layout;
+use crate::engine::scope::{PathSplitter, Scopes, Value};
 
 pub trait SheetProcessor {
-    fn process(&mut self, item: &Element) -> Result<(), SpreadSheetError>;
+    /// Process a resolved element. The layout holds the cursor position the
+    /// element applies to; the VM advances it once the call returns.
+    fn process(&mut self, item: &Element, layout: &Layout) -> Result<(), SpreadSheetError>;
 }
 
 pub struct VM {
     pub scopes: Scopes,
+    pub layout: Layout,
+    /// The last row covered by the rows emitted so far, merged cells
+    /// included.
+    last_row: Option<u32>,
+    /// Whether the rows are written inside a table block, where cells
+    /// cannot span several columns or rows.
+    in_table: bool,
 }
 
 impl Default for VM {
     fn default() -> Self {
         Self {
             scopes: Scopes::new(),
+            layout: Layout::new(),
+            last_row: None,
+            in_table: false,
         }
     }
 }
@@ -31,11 +48,102 @@ impl VM {
             match item {
                 Element::Format(format) => {
                     let format = self.resolve_format(format)?;
-                    processor.process(&Element::Format(format))?
+                    self.emit(&Element::Format(format), processor)?;
+                }
+                Element::Properties(properties) => {
+                    let mut resolved = Vec::new();
+                    for property in &properties.properties {
+                        resolved.push(Property {
+                            kind: property.kind,
+                            args: property
+                                .args
+                                .iter()
+                                .map(|arg| self.resolve_value(arg))
+                                .collect::<Result<_, _>>()?,
+                        });
+                    }
+                    let properties = Properties {
+                        properties: resolved,
+                    };
+                    self.emit(&Element::Properties(properties), processor)?;
+                }
+                Element::Sheet(sheet) => {
+                    let sheet = self.resolve_sheet(sheet)?;
+                    self.emit(&Element::Sheet(sheet), processor)?;
                 }
                 Element::Row(row) => {
                     let row = self.resolve(row)?;
-                    processor.process(&Element::Row(row))?;
+                    self.emit(&Element::Row(row), processor)?;
+                }
+                Element::Anchor(anchor) => {
+                    let name = self.resolve_expr(&anchor.name)?;
+                    let anchor = Anchor {
+                        name: Expr::Primary(Expression::Value(name)),
+                    };
+                    self.emit(&Element::Anchor(anchor), processor)?;
+                }
+                Element::Conditional(conditional) => {
+                    let conditional = self.resolve_conditional(conditional)?;
+                    self.emit(&Element::Conditional(conditional), processor)?;
+                }
+                Element::Validation(validation) => {
+                    let validation = self.resolve_validation(validation)?;
+                    self.emit(&Element::Validation(validation), processor)?;
+                }
+                Element::Chart(chart) => {
+                    let chart = self.resolve_chart(chart)?;
+                    self.emit(&Element::Chart(chart), processor)?;
+                }
+                Element::Table(table) => {
+                    self.table(table, processor)?;
+                }
+                Element::AutoFilter(autofilter) => {
+                    let autofilter = self.resolve_autofilter(autofilter)?;
+                    self.emit(&Element::AutoFilter(autofilter), processor)?;
+                }
+                Element::DefinedName(defined_name) => {
+                    let defined_name = DefinedName {
+                        name: self.resolve_value(&defined_name.name)?,
+                        range: self.resolve_range(&defined_name.range)?,
+                        local: defined_name.local,
+                    };
+                    self.emit(&Element::DefinedName(defined_name), processor)?;
+                }
+                Element::BorderRange(border_range) => {
+                    let border_range = BorderRange {
+                        range: self.resolve_range(&border_range.range)?,
+                        style: self.resolve_value(&border_range.style)?,
+                        color: border_range
+                            .color
+                            .as_ref()
+                            .map(|color| self.resolve_value(color))
+                            .transpose()?,
+                    };
+                    self.emit(&Element::BorderRange(border_range), processor)?;
+                }
+                Element::FillRange(fill_range) => {
+                    let fill_range = FillRange {
+                        range: self.resolve_range(&fill_range.range)?,
+                        color: self.resolve_value(&fill_range.color)?,
+                    };
+                    self.emit(&Element::FillRange(fill_range), processor)?;
+                }
+                Element::Protect(protect) => {
+                    let protect = Protect {
+                        password: self.resolve_password(&protect.password)?,
+                        allow: protect.allow.clone(),
+                    };
+                    self.emit(&Element::Protect(protect), processor)?;
+                }
+                Element::ProtectWorkbook(protect) => {
+                    let protect = ProtectWorkbook {
+                        password: self.resolve_password(&protect.password)?,
+                    };
+                    self.emit(&Element::ProtectWorkbook(protect), processor)?;
+                }
+                Element::PageSetup(page_setup) => {
+                    let page_setup = self.resolve_page_setup(page_setup)?;
+                    self.emit(&Element::PageSetup(page_setup), processor)?;
                 }
                 Element::ForLoop(for_loop) => {
                     self.for_loop(for_loop, processor)?;
@@ -47,13 +155,42 @@ impl VM {
                     self.for_each_header(for_each_header, processor)?;
                 }
                 _ => {
-                    processor.process(item)?;
+                    self.emit(item, processor)?;
                 }
             }
         }
         Ok(())
     }
 
+    /// Hands a resolved element to the processor, then moves the cursor past it.
+    fn emit(
+        &mut self,
+        item: &Element,
+        processor: &mut impl SheetProcessor,
+    ) -> Result<(), SpreadSheetError> {
+        let mut rowspan = 1;
+        if let Element::Row(row) = item {
+            for item in &row.cells {
+                if let RowItem::Cell(cell) = item {
+                    // Excel tables cannot hold merged cells.
+                    if self.in_table && (cell.colspan > 1 || cell.rowspan > 1) {
+                        return Err(SpreadSheetError::new(
+                            "Cells in a table cannot span several columns or rows".to_string(),
+                        ));
+                    }
+                    rowspan = rowspan.max(cell.rowspan);
+                }
+            }
+        }
+        processor.process(item, &self.layout)?;
+        if let Element::Row(_) = item {
+            let end_row = self.layout.row + rowspan as u32 - 1;
+            self.last_row = Some(self.last_row.map_or(end_row, |row| row.max(end_row)));
+        }
+        self.layout.advance(item);
+        Ok(())
+    }
+
     pub fn for_loop<'a>(
         &mut self,
         for_loop: &'a ForLoop<'a>,
@@ -97,8 +234,7 @@ impl VM {
         processor: &mut impl SheetProcessor,
     ) -> Result<(), SpreadSheetError> {
         let cells = self.resolve_for_each_header(for_each_header)?;
-        processor.process(&Element::Row(Row { cells }))?;
-        Ok(())
+        self.emit(&Element::Row(Row { cells }), processor)
     }
 
     pub fn eval_condition(&self, condition: &Condition) -> Result<bool, SpreadSheetError> {
@@ -127,6 +263,8 @@ impl VM {
             Expression::Identifier(id) => {
                 if let Some(v) = self.scopes.resolve(Expression::Identifier(id)) {
                     v
+                } else if let Some(v) = self.resolve_builtin(id) {
+                    v
                 } else {
                     return Err(SpreadSheetError::new(format!(
                         "Unresolved identifier: {}",
@@ -182,6 +320,31 @@ impl VM {
                 }
             }
             Expr::Primary(expr) => self.resolve_expression(expr),
+            Expr::Anchor(name, field) => {
+                let name = self.resolve_expr(name.as_ref())?.as_str();
+                let (row, col) = self
+                    .layout
+                    .anchor(&name)
+                    .ok_or_else(|| SpreadSheetError::new(format!("Unknown anchor: {}", name)))?;
+                match *field {
+                    "row" => Ok(Value::Integer(row as i64)),
+                    "col" => Ok(Value::Integer(col as i64)),
+                    _ => Err(SpreadSheetError::new(format!(
+                        "Unknown anchor field: {}",
+                        field
+                    ))),
+                }
+            }
+        }
+    }
+
+    /// Resolves the builtin variables that are not stored in the scopes,
+    /// currently only `$cursor` with its `row` and `col` fields.
+    fn resolve_builtin(&self, id: &str) -> Option<Value> {
+        let mut path = PathSplitter::new(&id[1..]);
+        match path.next() {
+            Some("cursor") => self.layout.cursor_value().resolve(&mut path).cloned(),
+            _ => None,
         }
     }
 
@@ -190,15 +353,7 @@ impl VM {
         for item in &row.cells {
             match item {
                 RowItem::Cell(cell) => {
-                    let v = self.resolve_expr(&cell.value)?;
-                    cells.push(RowItem::Cell(Cell {
-                        cell_type: cell.cell_type,
-                        value: Expr::Primary(Expression::Value(v)),
-                        format: cell.format,
-                        colspan: cell.colspan,
-                        rowspan: cell.rowspan,
-                        image_mode: cell.image_mode,
-                    }));
+                    cells.push(RowItem::Cell(self.resolve_cell(cell)?));
                 }
                 RowItem::ForEachCell(for_each) => {
                     let value = self.resolve_expression(&for_each.expression)?;
@@ -207,15 +362,7 @@ impl VM {
                             self.scopes.enter();
                             self.scopes.top.define("index", Value::Integer(i as i64));
                             self.scopes.top.define(&for_each.variable[1..], v.clone());
-                            let resolved_val = self.resolve_expr(&for_each.cell.value)?;
-                            cells.push(RowItem::Cell(Cell {
-                                cell_type: for_each.cell.cell_type,
-                                value: Expr::Primary(Expression::Value(resolved_val)),
-                                format: for_each.cell.format,
-                                colspan: for_each.cell.colspan,
-                                rowspan: for_each.cell.rowspan,
-                                image_mode: for_each.cell.image_mode,
-                            }));
+                            cells.push(RowItem::Cell(self.resolve_cell(&for_each.cell)?));
                             self.scopes.exit();
                         }
                     }
@@ -244,6 +391,366 @@ impl VM {
         })
     }
 
+    /// Evaluates an expression into a constant one.
+    fn resolve_value<'b>(&self, expr: &Expr) -> Result<Expr<'b>, SpreadSheetError> {
+        Ok(Expr::Primary(Expression::Value(self.resolve_expr(expr)?)))
+    }
+
+    pub fn resolve_cell<'b>(&self, cell: &Cell<'b>) -> Result<Cell<'b>, SpreadSheetError> {
+        let mut rich = Vec::new();
+        for segment in &cell.rich {
+            rich.push(RichSegment {
+                text: self.resolve_value(&segment.text)?,
+                format: segment.format,
+            });
+        }
+        let value = if matches!(cell.cell_type, CellType::Rich) {
+            let text: String = rich.iter().map(|segment| segment.text.as_str()).collect();
+            Value::String(text)
+        } else {
+            self.resolve_expr(&cell.value)?
+        };
+        Ok(Cell {
+            cell_type: cell.cell_type,
+            value: Expr::Primary(Expression::Value(value)),
+            text: cell
+                .text
+                .as_ref()
+                .map(|text| self.resolve_value(text))
+                .transpose()?,
+            rich,
+            format: cell.format,
+            colspan: cell.colspan,
+            rowspan: cell.rowspan,
+            image_mode: cell.image_mode,
+            image_options: self.resolve_image_options(&cell.image_options)?,
+            note: self.resolve_note(&cell.note)?,
+        })
+    }
+
+    pub fn resolve_sheet<'b>(&self, sheet: &Sheet<'b>) -> Result<Sheet<'b>, SpreadSheetError> {
+        let mut options = Vec::new();
+        for option in &sheet.options {
+            options.push(match option {
+                SheetOption::Freeze(cell_ref) => {
+                    SheetOption::Freeze(self.resolve_cell_ref(cell_ref)?)
+                }
+                SheetOption::Setting(kind, arg) => SheetOption::Setting(
+                    kind,
+                    arg.as_ref()
+                        .map(|arg| self.resolve_value(arg))
+                        .transpose()?,
+                ),
+            });
+        }
+        Ok(Sheet {
+            name: sheet.name.clone(),
+            options,
+        })
+    }
+
+    pub fn resolve_image_options<'b>(
+        &self,
+        options: &[ImageOption<'b>],
+    ) -> Result<Vec<ImageOption<'b>>, SpreadSheetError> {
+        options
+            .iter()
+            .map(|option| {
+                Ok(ImageOption {
+                    kind: option.kind,
+                    value: self.resolve_value(&option.value)?,
+                })
+            })
+            .collect()
+    }
+
+    pub fn resolve_note<'b>(
+        &self,
+        note: &Option<Note<'b>>,
+    ) -> Result<Option<Note<'b>>, SpreadSheetError> {
+        let Some(note) = note else {
+            return Ok(None);
+        };
+        Ok(Some(Note {
+            text: self.resolve_value(&note.text)?,
+            author: note
+                .author
+                .as_ref()
+                .map(|author| self.resolve_value(author))
+                .transpose()?,
+            visible: note.visible,
+        }))
+    }
+
+    pub fn resolve_cell_ref<'b>(
+        &self,
+        cell_ref: &CellRef<'b>,
+    ) -> Result<CellRef<'b>, SpreadSheetError> {
+        Ok(CellRef {
+            anchor: self.resolve_value(&cell_ref.anchor)?,
+            row: cell_ref.row,
+            col: cell_ref.col,
+        })
+    }
+
+    pub fn resolve_range<'b>(&self, range: &Range<'b>) -> Result<Range<'b>, SpreadSheetError> {
+        Ok(Range {
+            from: self.resolve_cell_ref(&range.from)?,
+            to: range
+                .to
+                .as_ref()
+                .map(|to| self.resolve_cell_ref(to))
+                .transpose()?,
+        })
+    }
+
+    pub fn resolve_conditional<'b>(
+        &self,
+        conditional: &Conditional<'b>,
+    ) -> Result<Conditional<'b>, SpreadSheetError> {
+        let mut rules = Vec::new();
+        for rule in &conditional.rules {
+            let rule = match rule {
+                ConditionalRule::When(test, format) => {
+                    let test = match test {
+                        ConditionTest::Cell(op, value) => {
+                            ConditionTest::Cell(*op, self.resolve_value(value)?)
+                        }
+                        ConditionTest::Between { negated, low, high } => ConditionTest::Between {
+                            negated: *negated,
+                            low: self.resolve_value(low)?,
+                            high: self.resolve_value(high)?,
+                        },
+                        ConditionTest::Text(op, value) => {
+                            ConditionTest::Text(op, self.resolve_value(value)?)
+                        }
+                        ConditionTest::Top {
+                            bottom,
+                            rank,
+                            percent,
+                        } => ConditionTest::Top {
+                            bottom: *bottom,
+                            rank: *rank,
+                            percent: *percent,
+                        },
+                        ConditionTest::Duplicate { unique } => {
+                            ConditionTest::Duplicate { unique: *unique }
+                        }
+                    };
+                    ConditionalRule::When(test, format)
+                }
+                ConditionalRule::DataBar(color) => ConditionalRule::DataBar(
+                    color.as_ref().map(|c| self.resolve_value(c)).transpose()?,
+                ),
+                ConditionalRule::ColorScale2(colors) => ConditionalRule::ColorScale2(
+                    colors
+                        .iter()
+                        .map(|c| self.resolve_value(c))
+                        .collect::<Result<_, _>>()?,
+                ),
+                ConditionalRule::ColorScale3(colors) => ConditionalRule::ColorScale3(
+                    colors
+                        .iter()
+                        .map(|c| self.resolve_value(c))
+                        .collect::<Result<_, _>>()?,
+                ),
+                ConditionalRule::IconSet(icons) => {
+                    ConditionalRule::IconSet(self.resolve_value(icons)?)
+                }
+            };
+            rules.push(rule);
+        }
+        Ok(Conditional {
+            range: self.resolve_range(&conditional.range)?,
+            rules,
+        })
+    }
+
+    pub fn resolve_validation<'b>(
+        &self,
+        validation: &Validation<'b>,
+    ) -> Result<Validation<'b>, SpreadSheetError> {
+        let mut rules = Vec::new();
+        for rule in &validation.rules {
+            rules.push(ValidationRule {
+                kind: rule.kind,
+                args: rule
+                    .args
+                    .iter()
+                    .map(|arg| self.resolve_value(arg))
+                    .collect::<Result<_, _>>()?,
+            });
+        }
+        Ok(Validation {
+            range: self.resolve_range(&validation.range)?,
+            rules,
+        })
+    }
+
+    /// Writes the header and the rows of a table, then emits the table
+    /// itself with the region it covers.
+    ///
+    /// The table is as wide as its header row, one column per cell. A table
+    /// without data rows still gets an empty one, as Excel requires.
+    fn table<'b>(
+        &mut self,
+        table: &'b Table<'b>,
+        processor: &mut impl SheetProcessor,
+    ) -> Result<(), SpreadSheetError> {
+        let (first_row, first_col) = (self.layout.row, self.layout.col);
+        let header = Element::Row(self.resolve(&table.header)?);
+        let in_table = std::mem::replace(&mut self.in_table, true);
+        self.emit(&header, processor)?;
+        // The table ends with the last row its body wrote, whatever moves
+        // of the cursor follow it.
+        let outer_last_row = self.last_row.take();
+        self.run(&table.body, processor)?;
+        let body_last_row = self.last_row;
+        self.last_row = outer_last_row.max(body_last_row);
+        self.in_table = in_table;
+
+        let Element::Row(header) = header else {
+            unreachable!("the header is a row");
+        };
+        let width = header
+            .cells
+            .iter()
+            .filter(|item| matches!(item, RowItem::Cell(_)))
+            .count() as u16;
+
+        let mut last_row = body_last_row.unwrap_or(first_row + 1).max(first_row + 1);
+        if !table.totals.is_empty() {
+            last_row += 1;
+        }
+        let mut totals = Vec::new();
+        for item in &table.totals {
+            totals.push(match item {
+                TotalsItem::Function(function) => TotalsItem::Function(function),
+                TotalsItem::Label(label) => TotalsItem::Label(self.resolve_value(label)?),
+            });
+        }
+        let table = Table {
+            name: self.resolve_value(&table.name)?,
+            style: table
+                .style
+                .as_ref()
+                .map(|style| self.resolve_value(style))
+                .transpose()?,
+            totals,
+            header,
+            body: Vec::new(),
+            region: Some((first_row, first_col, last_row, first_col + width.max(1) - 1)),
+        };
+        self.emit(&Element::Table(table), processor)
+    }
+
+    pub fn resolve_autofilter<'b>(
+        &self,
+        autofilter: &AutoFilter<'b>,
+    ) -> Result<AutoFilter<'b>, SpreadSheetError> {
+        let mut columns = Vec::new();
+        for column in &autofilter.columns {
+            let rule = match &column.rule {
+                FilterRule::List(values) => FilterRule::List(
+                    values
+                        .iter()
+                        .map(|value| self.resolve_value(value))
+                        .collect::<Result<_, _>>()?,
+                ),
+                FilterRule::Custom { tests, or } => {
+                    let mut resolved = Vec::new();
+                    for test in tests {
+                        resolved.push(match test {
+                            FilterTest::Compare(op, value) => {
+                                FilterTest::Compare(*op, self.resolve_value(value)?)
+                            }
+                            FilterTest::Text(op, value) => {
+                                FilterTest::Text(op, self.resolve_value(value)?)
+                            }
+                        });
+                    }
+                    FilterRule::Custom {
+                        tests: resolved,
+                        or: *or,
+                    }
+                }
+            };
+            columns.push(FilterColumn {
+                column: column.column,
+                rule,
+            });
+        }
+        Ok(AutoFilter {
+            range: self.resolve_range(&autofilter.range)?,
+            columns,
+        })
+    }
+
+    fn resolve_password<'b>(
+        &self,
+        password: &Option<Expr<'b>>,
+    ) -> Result<Option<Expr<'b>>, SpreadSheetError> {
+        password
+            .as_ref()
+            .map(|password| self.resolve_value(password))
+            .transpose()
+    }
+
+    pub fn resolve_page_setup<'b>(
+        &self,
+        page_setup: &PageSetup<'b>,
+    ) -> Result<PageSetup<'b>, SpreadSheetError> {
+        let mut rules = Vec::new();
+        for rule in &page_setup.rules {
+            rules.push(match rule {
+                PageRule::Range(kind, range) => PageRule::Range(kind, self.resolve_range(range)?),
+                PageRule::Setting(kind, args) => PageRule::Setting(
+                    kind,
+                    args.iter()
+                        .map(|arg| self.resolve_value(arg))
+                        .collect::<Result<_, _>>()?,
+                ),
+            });
+        }
+        Ok(PageSetup { rules })
+    }
+
+    pub fn resolve_chart<'b>(&self, chart: &Chart<'b>) -> Result<Chart<'b>, SpreadSheetError> {
+        let mut series = Vec::new();
+        for s in &chart.series {
+            series.push(ChartSeries {
+                values: self.resolve_range(&s.values)?,
+                categories: s
+                    .categories
+                    .as_ref()
+                    .map(|c| self.resolve_range(c))
+                    .transpose()?,
+                name: s.name.as_ref().map(|n| self.resolve_value(n)).transpose()?,
+            });
+        }
+        let mut options = Vec::new();
+        for option in &chart.options {
+            options.push(ChartOption {
+                kind: option.kind,
+                args: option
+                    .args
+                    .iter()
+                    .map(|arg| self.resolve_value(arg))
+                    .collect::<Result<_, _>>()?,
+            });
+        }
+        Ok(Chart {
+            kind: chart.kind,
+            position: chart
+                .position
+                .as_ref()
+                .map(|p| self.resolve_cell_ref(p))
+                .transpose()?,
+            series,
+            options,
+        })
+    }
+
     fn resolve_for_each_header<'b>(
         &self,
         for_each_header: &'b ForEachHeader,
@@ -273,7 +780,8 @@ impl VM {
                     "header() array items must be tuples of [text, span]".to_string(),
                 ));
             };
-            let text = tuple.first()
+            let text = tuple
+                .first()
                 .ok_or_else(|| {
                     SpreadSheetError::new("header tuple missing text field".to_string())
                 })?
@@ -293,12 +801,140 @@ impl VM {
             cells.push(RowItem::Cell(Cell {
                 cell_type: crate::engine::ast::CellType::Str,
                 value: Expr::Primary(Expression::Value(Value::String(text))),
+                text: None,
+                rich: Vec::new(),
                 format: for_each_header.format,
                 colspan: span,
                 rowspan: 1,
                 image_mode: None,
+                image_options: Vec::new(),
+                note: None,
             }));
         }
         Ok(cells)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::parser::parse_stream;
+
+    /// Records the regions of the tables it is given.
+    #[derive(Default)]
+    struct Tables(Vec<(u32, u16, u32, u16)>);
+
+    impl SheetProcessor for Tables {
+        fn process(&mut self, item: &Element, _layout: &Layout) -> Result<(), SpreadSheetError> {
+            if let Element::Table(table) = item {
+                self.0.push(table.region.unwrap());
+            }
+            Ok(())
+        }
+    }
+
+    fn table_regions(template: &str) -> Result<Vec<(u32, u16, u32, u16)>, SpreadSheetError> {
+        let tree = parse_stream(template).unwrap();
+        let mut tables = Tables::default();
+        VM::default().run(&tree.elements, &mut tables)?;
+        Ok(tables.0)
+    }
+
+    #[test]
+    fn table_covers_the_header_and_every_body_row() {
+        let regions = table_regions(
+            r#"
+sheet("S")
+[ str("before") ]
+table("T") {
+  [ str("A"), str("B"), str("C") ]
+  [ num(1), num(2), num(3) ]
+  [ num(4), num(5), num(6) ]
+  [ num(7), num(8), num(9) ]
+}
+"#,
+        )
+        .unwrap();
+        assert_eq!(regions, [(1, 0, 4, 2)]);
+    }
+
+    #[test]
+    fn table_ends_with_its_last_written_row() {
+        let regions = table_regions(
+            r#"
+sheet("S")
+table("T") {
+  [ str("A"), str("B") ]
+  [ num(1), num(2) ]
+  cr
+  cr
+}
+table("U") {
+  [ str("A") ]
+  [ num(1) ]
+  move(3, 0)
+}
+"#,
+        )
+        .unwrap();
+        assert_eq!(regions, [(0, 0, 1, 1), (2, 0, 3, 0)]);
+    }
+
+    #[test]
+    fn table_adds_its_totals_row() {
+        let regions = table_regions(
+            r#"
+sheet("S")
+table("T") totals("Total", sum) {
+  [ str("A"), str("B") ]
+  [ num(1), num(2) ]
+  [ num(3), num(4) ]
+}
+[ str("after") ]
+"#,
+        )
+        .unwrap();
+        assert_eq!(regions, [(0, 0, 3, 1)]);
+    }
+
+    #[test]
+    fn empty_table_keeps_one_data_row() {
+        let regions = table_regions(
+            r#"
+sheet("S")
+table("T") {
+  [ str("A"), str("B") ]
+}
+table("U") totals("Total", sum) {
+  [ str("A"), str("B") ]
+}
+"#,
+        )
+        .unwrap();
+        assert_eq!(regions, [(0, 0, 1, 1), (2, 0, 4, 1)]);
+    }
+
+    #[test]
+    fn table_cells_cannot_span() {
+        for row in [
+            r#"[ str("A", colspan(2)), str("B") ]"#,
+            r#"[ str("A"), str("B", rowspan(2)) ]"#,
+        ] {
+            let header = format!("sheet(\"S\")\ntable(\"T\") {{\n  {}\n}}\n", row);
+            assert!(table_regions(&header).is_err(), "{}", header);
+            let body = format!(
+                "sheet(\"S\")\ntable(\"T\") {{\n  [ str(\"A\"), str(\"B\") ]\n  {}\n}}\n",
+                row
+            );
+            assert!(table_regions(&body).is_err(), "{}", body);
+        }
+        let after = r#"
+sheet("S")
+table("T") {
+  [ str("A") ]
+}
+[ str("A", colspan(2)) ]
+"#;
+        assert!(table_regions(after).is_ok());
+    }
+}
@@ -1,3 +1,6 @@
+pub mod assets;
 pub mod csv;
 pub mod engine;
+pub mod numfmt;
+pub mod sanitize;
 pub mod xlsx;
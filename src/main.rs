@@ -104,6 +104,9 @@ fn main() {
                     println!("{:?}", e);
                 }
             }
+            for warning in &writer.warnings {
+                eprintln!("warning: {}", warning);
+            }
             let res = writer.save("output.xlsx");
             match res {
                 Ok(_) => {
@@ -0,0 +1,815 @@
+//! Text rendering of numbers and dates with Excel style format patterns,
+//! for the outputs that cannot store the pattern next to the value.
+
+/// The broken down parts of an ISO 8601 date and time.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: f64,
+}
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+const WEEKDAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+
+impl DateTime {
+    /// Parses the forms accepted by `date()` cells: `yyyy-mm-dd`,
+    /// `hh:mm[:ss[.fff]]` and the two joined by `T` or a space, optionally
+    /// followed by `Z`.
+    pub fn parse(s: &str) -> Option<DateTime> {
+        let s = s.trim().trim_end_matches('Z');
+        let (date, time) = match s.split_once(['T', ' ']) {
+            Some((date, time)) => (Some(date), Some(time)),
+            None if s.contains(':') => (None, Some(s)),
+            None => (Some(s), None),
+        };
+
+        let mut dt = DateTime::default();
+        if let Some(date) = date {
+            let mut parts = date.split('-');
+            dt.year = parts.next()?.parse().ok()?;
+            dt.month = parts.next()?.parse().ok()?;
+            dt.day = parts.next()?.parse().ok()?;
+            if parts.next().is_some()
+                || !(1..=12).contains(&dt.month)
+                || dt.day == 0
+                || dt.day > days_in_month(dt.year, dt.month)
+            {
+                return None;
+            }
+        }
+        if let Some(time) = time {
+            let mut parts = time.split(':');
+            dt.hour = parts.next()?.parse().ok()?;
+            dt.minute = parts.next()?.parse().ok()?;
+            dt.second = match parts.next() {
+                Some(second) => second.parse().ok()?,
+                None => 0.0,
+            };
+            if parts.next().is_some() || dt.hour > 23 || dt.minute > 59 || dt.second >= 60.0 {
+                return None;
+            }
+        }
+        Some(dt)
+    }
+
+    /// Whether the date part was given. A time alone, e.g. `12:30`, has
+    /// no year, month or day.
+    pub fn has_date(&self) -> bool {
+        self.month != 0
+    }
+}
+
+/// Days since the Excel epoch, 1899-12-30, for a date of the civil calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468 + 25569
+}
+
+/// The year, month and day of a number of days since the Excel epoch.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    // Shifted to the 0000-03-01 epoch of the civil algorithm.
+    let z = days - 25569 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => {
+            29
+        }
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// A piece of a format pattern.
+#[derive(Debug, PartialEq)]
+enum Token {
+    Literal(String),
+    /// A run of the same date letter, e.g. `yyyy` or `mm`.
+    Date(char, usize),
+    /// Elapsed time in brackets, e.g. `[h]` or `[mm]`.
+    Elapsed(char, usize),
+    AmPm,
+    /// Any of `0`, `#`, `?`, `.`, `,` and `%`.
+    Number(char),
+    /// The exponent of a scientific pattern, `E+`, `E-`, `e+` or `e-`.
+    Exponent(char, char),
+    /// A part of a pattern that cannot be rendered as text, e.g. a condition.
+    Unsupported,
+}
+
+/// Splits a pattern into sections at the `;` characters outside of quotes.
+fn sections(pattern: &str) -> Vec<&str> {
+    let mut sections = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in pattern.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                sections.push(&pattern[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    sections.push(&pattern[start..]);
+    sections
+}
+
+fn tokenize(section: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = section.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            '"' => {
+                let mut literal = String::new();
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    literal.push(chars[i]);
+                    i += 1;
+                }
+                tokens.push(Token::Literal(literal));
+            }
+            '\\' => {
+                if let Some(next) = chars.get(i + 1) {
+                    tokens.push(Token::Literal(next.to_string()));
+                    i += 1;
+                }
+            }
+            '[' => {
+                // Colors and locales have no meaning in text, but the symbol
+                // of a currency, e.g. `[$€-407]`, is kept.
+                let start = i + 1;
+                while i < chars.len() && chars[i] != ']' {
+                    i += 1;
+                }
+                let bracket: String = chars[start..i.min(chars.len())].iter().collect();
+                match bracket.chars().next().map(|c| c.to_ascii_lowercase()) {
+                    Some('$') => {
+                        let symbol = bracket[1..].split('-').next().unwrap_or_default();
+                        tokens.push(Token::Literal(symbol.to_string()));
+                    }
+                    Some(letter @ ('h' | 'm' | 's'))
+                        if bracket.chars().all(|c| c.to_ascii_lowercase() == letter) =>
+                    {
+                        tokens.push(Token::Elapsed(letter, bracket.len()));
+                    }
+                    Some('<' | '>' | '=') => tokens.push(Token::Unsupported),
+                    _ => {}
+                }
+            }
+            'E' | 'e' if matches!(chars.get(i + 1), Some('+' | '-')) => {
+                tokens.push(Token::Exponent(c, chars[i + 1]));
+                i += 1;
+            }
+            '_' | '*' => {
+                // Padding to the width of the next character.
+                i += 1;
+            }
+            '0' | '#' | '?' | '.' | ',' | '%' => tokens.push(Token::Number(c)),
+            _ if chars[i..]
+                .iter()
+                .take(5)
+                .collect::<String>()
+                .eq_ignore_ascii_case("AM/PM") =>
+            {
+                tokens.push(Token::AmPm);
+                i += 4;
+            }
+            'y' | 'Y' | 'm' | 'M' | 'd' | 'D' | 'h' | 'H' | 's' | 'S' => {
+                let letter = c.to_ascii_lowercase();
+                let mut n = 1;
+                while i + n < chars.len() && chars[i + n].to_ascii_lowercase() == letter {
+                    n += 1;
+                }
+                tokens.push(Token::Date(letter, n));
+                i += n - 1;
+            }
+            _ => tokens.push(Token::Literal(c.to_string())),
+        }
+        i += 1;
+    }
+    tokens
+}
+
+fn is_text_pattern(pattern: &str) -> bool {
+    pattern.is_empty() || pattern.eq_ignore_ascii_case("general") || pattern == "@"
+}
+
+/// Renders a number without a pattern, e.g. `1234` or `1234.5`.
+pub fn format_general(value: f64, decimal_separator: char) -> String {
+    let text = value.to_string();
+    if decimal_separator == '.' {
+        text
+    } else {
+        text.replace('.', &decimal_separator.to_string())
+    }
+}
+
+/// Renders a number with an Excel number format, e.g. `#,##0.00` or `0.0%`.
+///
+/// Literal text, sections for negative numbers and zero, percentages,
+/// thousands separators and scientific notation are supported. Patterns made
+/// of date letters or elapsed times such as `[h]:mm` render the number as an
+/// Excel serial date. Patterns that cannot be followed in text, with
+/// fractions or conditions, render the number as [`format_general`] does.
+pub fn format_number(value: f64, pattern: &str, decimal_separator: char) -> String {
+    if is_text_pattern(pattern) {
+        return format_general(value, decimal_separator);
+    }
+
+    let sections = sections(pattern);
+    let (section, value) = if value < 0.0 && sections.len() > 1 {
+        (sections[1], -value)
+    } else if value == 0.0 && sections.len() > 2 {
+        (sections[2], value)
+    } else {
+        (sections[0], value)
+    };
+    let tokens = tokenize(section);
+
+    if tokens.contains(&Token::Unsupported) {
+        return format_general(value, decimal_separator);
+    }
+    if tokens
+        .iter()
+        .any(|t| matches!(t, Token::Date(..) | Token::Elapsed(..)))
+    {
+        return render_date(&from_serial(value), &tokens)
+            .unwrap_or_else(|| format_general(value, decimal_separator));
+    }
+    if is_fraction(&tokens) {
+        return format_general(value, decimal_separator);
+    }
+    if let Some(e) = tokens.iter().position(|t| matches!(t, Token::Exponent(..))) {
+        return render_scientific(value, &tokens, e, decimal_separator);
+    }
+
+    render_number(value, &tokens, decimal_separator)
+}
+
+/// Renders a date with an Excel date format, e.g. `yyyy-mm-dd hh:mm`.
+///
+/// There is no text for a pattern that cannot be followed in text, nor for
+/// the year, month and day letters of a time without a date.
+pub fn format_date(date: &DateTime, pattern: &str) -> Option<String> {
+    let tokens = tokenize(sections(pattern)[0]);
+    if tokens.contains(&Token::Unsupported) {
+        return None;
+    }
+    render_date(date, &tokens)
+}
+
+/// Whether a number pattern shows a fraction, e.g. `# ?/?`.
+fn is_fraction(tokens: &[Token]) -> bool {
+    tokens.iter().any(|t| *t == Token::Literal("/".to_string()))
+        && tokens
+            .iter()
+            .any(|t| matches!(t, Token::Number('0' | '#' | '?')))
+}
+
+fn is_digit(token: &Token) -> bool {
+    matches!(token, Token::Number('0' | '#' | '?'))
+}
+
+/// Renders a number in scientific notation: the mantissa with the tokens
+/// before the exponent at `e`, the exponent with the digits after it.
+///
+/// With several integer digits and a `#` among them, e.g. `##0.0E+0`, the
+/// exponent is a multiple of their count, as in engineering notation.
+fn render_scientific(value: f64, tokens: &[Token], e: usize, decimal_separator: char) -> String {
+    let Token::Exponent(letter, sign) = tokens[e] else {
+        unreachable!("no exponent at {}", e);
+    };
+    let mantissa_tokens = &tokens[..e];
+    let digits = tokens[e + 1..].iter().take_while(|t| is_digit(t)).count();
+
+    let int_part = mantissa_tokens
+        .iter()
+        .take_while(|t| **t != Token::Number('.'));
+    let int_digits = int_part.clone().filter(|t| is_digit(t)).count().max(1) as i32;
+    let engineering = int_digits > 1 && int_part.clone().any(|t| *t == Token::Number('#'));
+    let decimals = mantissa_tokens
+        .iter()
+        .skip_while(|t| **t != Token::Number('.'))
+        .filter(|t| is_digit(t))
+        .count();
+
+    let exponent_of = |value: f64| {
+        if value == 0.0 {
+            return 0;
+        }
+        let magnitude = value.abs().log10().floor() as i32;
+        if engineering {
+            magnitude.div_euclid(int_digits) * int_digits
+        } else {
+            magnitude - (int_digits - 1)
+        }
+    };
+    let mut exponent = exponent_of(value);
+    // Rounding the mantissa may carry it into the next power of ten, e.g.
+    // 9.99 with `0.0E+0`.
+    let rounded: f64 = format!("{:.*}", decimals, value.abs() / 10f64.powi(exponent))
+        .parse()
+        .unwrap_or_default();
+    if exponent_of(rounded * 10f64.powi(exponent)) != exponent {
+        exponent = exponent_of(rounded * 10f64.powi(exponent));
+    }
+
+    let mut out = render_number(
+        value / 10f64.powi(exponent),
+        mantissa_tokens,
+        decimal_separator,
+    );
+    out.push(letter);
+    if exponent < 0 {
+        out.push('-');
+    } else if sign == '+' {
+        out.push('+');
+    }
+    out.push_str(&format!(
+        "{:0width$}",
+        exponent.abs(),
+        width = digits.max(1)
+    ));
+    for token in &tokens[e + 1 + digits..] {
+        if let Token::Literal(s) = token {
+            out.push_str(s);
+        }
+    }
+    out
+}
+
+fn render_number(value: f64, tokens: &[Token], decimal_separator: char) -> String {
+    let placeholders: Vec<char> = tokens
+        .iter()
+        .filter_map(|t| match t {
+            Token::Number(c) if *c != '%' => Some(*c),
+            _ => None,
+        })
+        .collect();
+    let percent = tokens.iter().filter(|t| **t == Token::Number('%')).count();
+
+    let point = placeholders.iter().position(|c| *c == '.');
+    let (int_part, frac_part) = match point {
+        Some(p) => (&placeholders[..p], &placeholders[p + 1..]),
+        None => (&placeholders[..], &placeholders[..0]),
+    };
+    let min_int = int_part.iter().filter(|c| **c == '0').count();
+    let grouping = {
+        let digits: Vec<usize> = int_part
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| **c != ',')
+            .map(|(i, _)| i)
+            .collect();
+        int_part
+            .iter()
+            .enumerate()
+            .any(|(i, c)| *c == ',' && digits.first() < Some(&i) && digits.last() > Some(&i))
+    };
+    // Commas after the last digit placeholder scale the number by 1000 each.
+    let scaling = placeholders.iter().rev().take_while(|c| **c == ',').count() as i32;
+    let decimals = frac_part.iter().filter(|c| **c != ',').count();
+    let min_decimals = frac_part.iter().filter(|c| **c == '0').count();
+
+    let value = value * 100f64.powi(percent as i32) / 1000f64.powi(scaling);
+    let negative = value < 0.0;
+    let mut text = format!("{:.*}", decimals, value.abs());
+
+    // Drop optional trailing zeros in the fraction.
+    if decimals > min_decimals {
+        let keep = text.len() - (decimals - min_decimals);
+        while text.len() > keep && text.ends_with('0') {
+            text.pop();
+        }
+        if text.ends_with('.') {
+            text.pop();
+        }
+    }
+
+    let (mut int_digits, frac_digits) = match text.split_once('.') {
+        Some((i, f)) => (i.to_string(), f.to_string()),
+        None => (text, String::new()),
+    };
+    if int_digits == "0" && min_int == 0 {
+        int_digits.clear();
+    }
+    while int_digits.len() < min_int {
+        int_digits.insert(0, '0');
+    }
+    if grouping {
+        let thousands = if decimal_separator == ',' { '.' } else { ',' };
+        let mut grouped = String::new();
+        for (i, c) in int_digits.chars().enumerate() {
+            if i > 0 && (int_digits.len() - i) % 3 == 0 {
+                grouped.push(thousands);
+            }
+            grouped.push(c);
+        }
+        int_digits = grouped;
+    }
+
+    let mut number = int_digits;
+    if !frac_digits.is_empty() {
+        number.push(decimal_separator);
+        number.push_str(&frac_digits);
+    }
+
+    let mut out = String::new();
+    if negative {
+        out.push('-');
+    }
+    let mut number_written = false;
+    for token in tokens {
+        match token {
+            Token::Literal(s) => out.push_str(s),
+            Token::Number('%') => out.push('%'),
+            Token::Number(_) => {
+                if !number_written {
+                    out.push_str(&number);
+                    number_written = true;
+                }
+            }
+            Token::AmPm
+            | Token::Date(..)
+            | Token::Elapsed(..)
+            | Token::Exponent(..)
+            | Token::Unsupported => {}
+        }
+    }
+    out
+}
+
+/// The parts of a date and time once rounded to the precision of a pattern.
+struct Clock {
+    /// Hundredths, thousandths... of a second since the Excel epoch.
+    ticks: i64,
+    /// Ticks per second.
+    scale: i64,
+}
+
+impl Clock {
+    fn new(date: &DateTime, tokens: &[Token]) -> Clock {
+        // The longest run of `0` after a `.`, e.g. 2 for `ss.00`.
+        let digits = tokens
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| **t == Token::Number('.'))
+            .map(|(i, _)| {
+                tokens[i + 1..]
+                    .iter()
+                    .take_while(|t| **t == Token::Number('0'))
+                    .count()
+            })
+            .max()
+            .unwrap_or(0)
+            .min(6);
+        let scale = 10i64.pow(digits as u32);
+        let days = if date.has_date() {
+            days_from_civil(date.year.into(), date.month.into(), date.day.into())
+        } else {
+            0
+        };
+        let seconds = f64::from(date.hour) * 3600.0 + f64::from(date.minute) * 60.0 + date.second;
+        // Rounded once, so 59.999 seconds with `ss.00` carry into the minute.
+        Clock {
+            ticks: days * 86400 * scale + (seconds * scale as f64).round() as i64,
+            scale,
+        }
+    }
+
+    fn days(&self) -> i64 {
+        self.ticks.div_euclid(86400 * self.scale)
+    }
+
+    /// The seconds since midnight.
+    fn seconds(&self) -> i64 {
+        self.ticks.rem_euclid(86400 * self.scale) / self.scale
+    }
+}
+
+/// Renders the tokens of a date pattern. There is no text for the year,
+/// month and day of a time without a date.
+fn render_date(date: &DateTime, tokens: &[Token]) -> Option<String> {
+    let twelve_hour = tokens.contains(&Token::AmPm);
+    let clock = Clock::new(date, tokens);
+    let (year, month, day) = civil_from_days(clock.days());
+    // The Excel epoch, 1899-12-30, was a Saturday.
+    let weekday = (clock.days() + 6).rem_euclid(7) as usize;
+    let seconds = clock.seconds();
+    let (hour, minute, second) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+
+    let mut out = String::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        match &tokens[i] {
+            Token::Literal(s) => out.push_str(s),
+            Token::Number('.') => {
+                // Fractional seconds, e.g. `ss.000`.
+                let digits = tokens[i + 1..]
+                    .iter()
+                    .take_while(|t| **t == Token::Number('0'))
+                    .count();
+                if digits > 0 {
+                    let fraction = format!(
+                        "{:0width$}",
+                        clock.ticks.rem_euclid(clock.scale),
+                        width = clock.scale.ilog10() as usize
+                    );
+                    out.push('.');
+                    out.push_str(&fraction[..digits.min(fraction.len())]);
+                    i += digits;
+                } else {
+                    out.push('.');
+                }
+            }
+            Token::Number('0') => {}
+            Token::Number(c) => out.push(*c),
+            Token::AmPm => out.push_str(if hour < 12 { "AM" } else { "PM" }),
+            Token::Elapsed(letter, n) => {
+                let total = clock.ticks / clock.scale;
+                let value = match letter {
+                    'h' => total / 3600,
+                    'm' => total / 60,
+                    _ => total,
+                };
+                out.push_str(&format!("{:0width$}", value, width = *n));
+            }
+            Token::Date('m', n) if is_minute(tokens, i) => pad(&mut out, minute as u32, *n),
+            Token::Date('h', n) => {
+                let hour = if twelve_hour {
+                    (hour + 11) % 12 + 1
+                } else {
+                    hour
+                };
+                pad(&mut out, hour as u32, *n);
+            }
+            Token::Date('s', n) => pad(&mut out, second as u32, *n),
+            Token::Date(_, _) if !date.has_date() => return None,
+            Token::Date('y', n) => {
+                if *n <= 2 {
+                    out.push_str(&format!("{:02}", year.rem_euclid(100)));
+                } else {
+                    out.push_str(&format!("{:04}", year));
+                }
+            }
+            Token::Date('m', n) => {
+                let name = MONTHS[month as usize - 1];
+                match n {
+                    1 | 2 => pad(&mut out, month as u32, *n),
+                    3 => out.push_str(&name[..3]),
+                    5 => out.push_str(&name[..1]),
+                    _ => out.push_str(name),
+                }
+            }
+            Token::Date('d', n) => {
+                let name = WEEKDAYS[weekday];
+                match n {
+                    1 | 2 => pad(&mut out, day as u32, *n),
+                    3 => out.push_str(&name[..3]),
+                    _ => out.push_str(name),
+                }
+            }
+            Token::Date(..) | Token::Exponent(..) | Token::Unsupported => {}
+        }
+        i += 1;
+    }
+    Some(out)
+}
+
+/// Excel reads `m` as minutes right after an hour or right before a second.
+fn is_minute(tokens: &[Token], i: usize) -> bool {
+    let letter = |t: &Token| match t {
+        Token::Date(c, _) | Token::Elapsed(c, _) => Some(*c),
+        _ => None,
+    };
+    let previous = tokens[..i].iter().rev().find_map(letter);
+    let next = tokens[i + 1..].iter().find_map(letter);
+    previous == Some('h') || next == Some('s')
+}
+
+fn pad(out: &mut String, value: u32, width: usize) {
+    out.push_str(&format!("{:0width$}", value, width = width.min(2)));
+}
+
+/// Converts an Excel serial date (days since 1899-12-30) to its parts. The
+/// seconds are left unrounded, for the precision of the pattern.
+fn from_serial(serial: f64) -> DateTime {
+    let days = serial.floor();
+    let (year, month, day) = civil_from_days(days as i64);
+    let seconds = (serial - days) * 86400.0;
+    DateTime {
+        year: year as u16,
+        month: month as u8,
+        day: day as u8,
+        hour: (seconds / 3600.0) as u8,
+        minute: (seconds % 3600.0 / 60.0) as u8,
+        second: seconds % 60.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> DateTime {
+        DateTime::parse(s).unwrap()
+    }
+
+    #[test]
+    fn sections_for_negative_and_zero() {
+        let pattern = "0.00;(0.00);\"zero\"";
+        assert_eq!(format_number(1.5, pattern, '.'), "1.50");
+        assert_eq!(format_number(-1.5, pattern, '.'), "(1.50)");
+        assert_eq!(format_number(0.0, pattern, '.'), "zero");
+        assert_eq!(format_number(-1.5, "0.00", '.'), "-1.50");
+    }
+
+    #[test]
+    fn grouping_and_scaling() {
+        assert_eq!(format_number(1234567.891, "#,##0.00", '.'), "1,234,567.89");
+        assert_eq!(format_number(123.0, "#,##0", '.'), "123");
+        assert_eq!(format_number(1234567.0, "#,##0,", '.'), "1,235");
+        assert_eq!(format_number(1234567.0, "0.0,,", '.'), "1.2");
+    }
+
+    #[test]
+    fn optional_digits() {
+        assert_eq!(format_number(1.5, "0.##", '.'), "1.5");
+        assert_eq!(format_number(2.0, "0.##", '.'), "2");
+        assert_eq!(format_number(0.5, "#.00", '.'), ".50");
+        assert_eq!(format_number(7.0, "000", '.'), "007");
+    }
+
+    #[test]
+    fn percent() {
+        assert_eq!(format_number(0.125, "0.0%", '.'), "12.5%");
+        assert_eq!(format_number(0.5, "0%", '.'), "50%");
+    }
+
+    #[test]
+    fn decimal_comma() {
+        assert_eq!(format_number(1234.5, "#,##0.00", ','), "1.234,50");
+        assert_eq!(format_general(1.25, ','), "1,25");
+    }
+
+    #[test]
+    fn currency_and_literals() {
+        assert_eq!(
+            format_number(1234.0, "[$€-407] #,##0.00", '.'),
+            "€ 1,234.00"
+        );
+        assert_eq!(format_number(5.0, "[Red]0\" pcs\"", '.'), "5 pcs");
+    }
+
+    #[test]
+    fn scientific() {
+        assert_eq!(format_number(12345.678, "0.00E+00", '.'), "1.23E+04");
+        assert_eq!(format_number(0.000123, "0.00E+00", '.'), "1.23E-04");
+        assert_eq!(format_number(-12345.678, "0.0E-0", '.'), "-1.2E4");
+        assert_eq!(format_number(0.000123, "0.0E-0", '.'), "1.2E-4");
+        assert_eq!(format_number(9.99, "0.0E+00", '.'), "1.0E+01");
+        assert_eq!(format_number(0.0, "0.00E+00", '.'), "0.00E+00");
+        assert_eq!(format_number(12345.678, "##0.0E+0", '.'), "12.3E+3");
+        assert_eq!(format_number(1234.5, "0.00E+00", ','), "1,23E+03");
+        assert_eq!(format_number(1234.6, "\"E-\"0", '.'), "E-1235");
+    }
+
+    #[test]
+    fn unsupported_patterns_fall_back_to_general() {
+        assert_eq!(format_number(1.5, "# ?/?", '.'), "1.5");
+        assert_eq!(format_number(1500.0, "[>=1000]0,\"K\";0", '.'), "1500");
+        let dt = date("2024-03-05");
+        assert_eq!(format_date(&dt, "[<1]hh:mm;yyyy"), None);
+    }
+
+    #[test]
+    fn general_patterns() {
+        assert_eq!(format_number(1.5, "General", '.'), "1.5");
+        assert_eq!(format_number(1.5, "@", ','), "1,5");
+    }
+
+    #[test]
+    fn date_tokens() {
+        let dt = date("2024-03-05T14:07:09");
+        assert_eq!(
+            format_date(&dt, "yyyy-mm-dd hh:mm:ss").unwrap(),
+            "2024-03-05 14:07:09"
+        );
+        assert_eq!(format_date(&dt, "d/m/yy").unwrap(), "5/3/24");
+        assert_eq!(
+            format_date(&dt, "ddd dd mmm yyyy").unwrap(),
+            "Tue 05 Mar 2024"
+        );
+        assert_eq!(format_date(&dt, "dddd mmmm").unwrap(), "Tuesday March");
+        assert_eq!(format_date(&dt, "mmmmm").unwrap(), "M");
+        assert_eq!(format_date(&dt, "h:mm AM/PM").unwrap(), "2:07 PM");
+    }
+
+    #[test]
+    fn fractional_seconds() {
+        let dt = date("12:00:01.25");
+        assert_eq!(format_date(&dt, "hh:mm:ss.00").unwrap(), "12:00:01.25");
+        let dt = date("10:00:59.999");
+        assert_eq!(format_date(&dt, "hh:mm:ss.00").unwrap(), "10:01:00.00");
+        assert_eq!(format_date(&dt, "hh:mm:ss.000").unwrap(), "10:00:59.999");
+        assert_eq!(format_date(&dt, "hh:mm:ss").unwrap(), "10:01:00");
+        let dt = date("2024-02-29T23:59:59.996");
+        assert_eq!(
+            format_date(&dt, "yyyy-mm-dd hh:mm:ss.00").unwrap(),
+            "2024-03-01 00:00:00.00"
+        );
+    }
+
+    #[test]
+    fn time_without_date() {
+        let dt = date("12:30");
+        assert_eq!(format_date(&dt, "hh:mm").unwrap(), "12:30");
+        assert_eq!(format_date(&dt, "h:mm AM/PM").unwrap(), "12:30 PM");
+        for pattern in ["dd mmm yyyy", "ddd hh:mm", "dddd", "mmmm", "yy"] {
+            assert_eq!(format_date(&dt, pattern), None, "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn elapsed_time() {
+        assert_eq!(format_number(1.5, "[h]:mm", '.'), "36:00");
+        assert_eq!(format_number(1.5, "[hh]:mm:ss", '.'), "36:00:00");
+        assert_eq!(format_number(0.0625, "[mm]:ss", '.'), "90:00");
+        assert_eq!(format_number(0.0625, "[s]", '.'), "5400");
+        assert_eq!(format_date(&date("01:02:03"), "[m]:ss").unwrap(), "62:03");
+    }
+
+    #[test]
+    fn serial_dates() {
+        assert_eq!(
+            format_number(45356.5, "yyyy-mm-dd hh:mm", '.'),
+            "2024-03-05 12:00"
+        );
+        assert_eq!(format_number(0.9999999, "hh:mm:ss", '.'), "00:00:00");
+        assert_eq!(
+            format_number(45356.9999999, "yyyy-mm-dd hh:mm:ss", '.'),
+            "2024-03-06 00:00:00"
+        );
+    }
+
+    #[test]
+    fn parse_rejects_invalid_dates() {
+        assert!(DateTime::parse("2024-02-31").is_none());
+        assert!(DateTime::parse("2023-02-29").is_none());
+        assert!(DateTime::parse("1900-02-29").is_none());
+        assert!(DateTime::parse("2024-13-01").is_none());
+        assert!(DateTime::parse("24:00").is_none());
+        assert_eq!(date("2024-02-29").day, 29);
+        assert_eq!(date("2000-02-29").day, 29);
+        assert_eq!(date("2024-04-30Z").day, 30);
+    }
+}
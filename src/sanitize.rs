@@ -0,0 +1,16 @@
+//! Protection against formula injection in exported text.
+
+/// Whether a spreadsheet application would treat the text as a formula when
+/// it is typed in or imported from CSV.
+pub fn is_formula_like(text: &str) -> bool {
+    text.starts_with(['=', '+', '-', '@', '\t', '\r'])
+}
+
+/// Prefixes formula-like text with a single quote, so it is kept as text.
+pub fn escape_formula(text: String) -> String {
+    if is_formula_like(&text) {
+        format!("'{}", text)
+    } else {
+        text
+    }
+}
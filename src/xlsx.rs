@@ -1,23 +1,96 @@
-use crate::engine::ast::{CellType, Element, Row, RowItem};
+use crate::assets::AssetResolver;
+use crate::engine::ast::{
+    Anchor, AutoFilter, BorderRange, Cell, CellRef, CellType, Chart as ChartElement, Column,
+    CompareOp, ConditionTest, Conditional, ConditionalRule, Defaults, DefinedName, Element, Expr,
+    FillRange, FilterRule, FilterTest, PageRule, PageSetup, Properties, Protect, Range, Row,
+    RowSpec, Sheet, SheetOption, Table as TableElement, TotalsItem, Validation,
+};
 use crate::engine::diag::SpreadSheetError;
+use crate::engine::layout::Layout;
+use crate::engine::scope::Value;
 use crate::engine::vm::SheetProcessor;
+use crate::sanitize::is_formula_like;
 use ecow::EcoString;
-use indexmap::IndexMap;
+use indexmap::{IndexMap, IndexSet};
+use rust_xlsxwriter::utility::{cell_range_absolute, row_col_to_cell, row_col_to_cell_absolute};
 use rust_xlsxwriter::{
-    ExcelDateTime, Format, FormatAlign, FormatBorder, FormatScript, FormatUnderline, Image,
-    Workbook, Worksheet, XlsxError,
+    Chart, ChartLegendPosition, Color, ConditionalFormat2ColorScale, ConditionalFormat3ColorScale,
+    ConditionalFormatCell, ConditionalFormatCellRule, ConditionalFormatDataBar,
+    ConditionalFormatDuplicate, ConditionalFormatIconSet, ConditionalFormatIconType,
+    ConditionalFormatText, ConditionalFormatTextRule, ConditionalFormatTop,
+    ConditionalFormatTopRule, DataValidation, DataValidationErrorStyle, DataValidationRule,
+    DocProperties, ExcelDateTime, FilterCondition, FilterCriteria, Format, FormatAlign,
+    FormatBorder, FormatDiagonalBorder, FormatPattern, FormatScript, FormatUnderline, Formula,
+    Image, IntoConditionalFormatValue, IntoDataValidationValue, Note, ProtectionOptions, Table,
+    TableColumn, TableFunction, TableStyle, Url, Workbook, Worksheet, XlsxError,
 };
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Cursor, Read, Write};
+use zip::write::SimpleFileOptions;
+use zip::{ZipArchive, ZipWriter};
 
 pub struct XlsxWriter {
     pub workbook: Workbook,
     pub worksheet: Option<Worksheet>,
-    pub row: u32,
-    pub col: u16,
-    pub anchors: IndexMap<EcoString, (u32, u16)>,
     pub formats: IndexMap<EcoString, Format>,
+    /// The declared formats with a `num()` pattern.
+    pub patterned_formats: HashSet<EcoString>,
     pub default_format: Format,
     pub date_format: Format,
     pub number_format: Format,
+    /// Write `str()` values starting with `=`, `+`, `-` or `@` with a quote
+    /// prefix, so they stay text when the cell is edited in Excel.
+    pub protect_formulas: bool,
+    /// A `freeze()` of the current sheet waiting for its anchor to be set:
+    /// the anchor name and the row and column offsets.
+    pub pending_freeze: Option<(EcoString, i32, i16)>,
+    /// The rows of the current sheet that start a new printed page.
+    pub page_breaks: Vec<u32>,
+    /// The formats set by `col()` and `row()` on the current sheet, used by
+    /// the cells there without a format of their own.
+    pub column_formats: BTreeMap<u16, EcoString>,
+    pub row_formats: BTreeMap<u32, EcoString>,
+    /// How the cells of the current sheet were formatted, by row, for
+    /// `border_range()` and `fill_range()` to add to.
+    pub written_cells: BTreeMap<u32, Vec<WrittenCell>>,
+    /// The formats set by `border_range()` and `fill_range()`.
+    pub range_formats: IndexSet<Format>,
+    /// Lock the structure of the workbook with an optional password, so
+    /// sheets cannot be added, removed, renamed or moved.
+    pub workbook_protection: Option<Option<String>>,
+    /// Fail on unknown format modifiers and invalid modifier values instead
+    /// of skipping them with a warning.
+    pub strict_formats: bool,
+    /// The problems found in the format declarations in lenient mode.
+    pub warnings: Vec<String>,
+    /// Where `img()` cells read their files from.
+    pub assets: AssetResolver,
+}
+
+/// The format a cell was written with, cheap enough to record for every
+/// cell.
+#[derive(Debug, Clone, Copy)]
+pub enum CellStyle {
+    /// A declared format, by its index in `formats`.
+    Declared(usize),
+    Number,
+    Date,
+    Text,
+    /// Excel's hyperlink style of a `url()` cell without a format.
+    Hyperlink,
+    /// A format set by `border_range()` or `fill_range()`, by its index in
+    /// `range_formats`.
+    Range(usize),
+}
+
+/// The style of the cells from `first_col` to `last_col` of a row.
+#[derive(Debug, Clone, Copy)]
+pub struct WrittenCell {
+    pub first_col: u16,
+    pub last_col: u16,
+    pub style: CellStyle,
+    /// Written with a quote prefix, see `protect_formulas`.
+    pub quoted: bool,
 }
 
 impl Default for XlsxWriter {
@@ -25,65 +98,143 @@ impl Default for XlsxWriter {
         XlsxWriter {
             workbook: Workbook::new(),
             worksheet: None,
-            row: 0,
-            col: 0,
-            anchors: IndexMap::new(),
             formats: IndexMap::new(),
+            patterned_formats: HashSet::new(),
             default_format: Format::new(),
             date_format: Format::new().set_num_format("dd/mm/yyyy hh:mm"),
             number_format: Format::new().set_num_format("0.00"),
+            protect_formulas: false,
+            pending_freeze: None,
+            page_breaks: Vec::new(),
+            column_formats: BTreeMap::new(),
+            row_formats: BTreeMap::new(),
+            written_cells: BTreeMap::new(),
+            range_formats: IndexSet::new(),
+            workbook_protection: None,
+            strict_formats: false,
+            warnings: Vec::new(),
+            assets: AssetResolver::default(),
         }
     }
 }
 
 impl XlsxWriter {
+    /// Sets the format of `num()` cells without a format of their own.
+    pub fn with_number_format(mut self, format: Format) -> Self {
+        self.number_format = format;
+        self
+    }
+
+    /// Sets the format of `date()` cells without a format of their own.
+    pub fn with_date_format(mut self, format: Format) -> Self {
+        self.date_format = format;
+        self
+    }
+
+    /// Sets the format of `str()` and other text cells without a format of
+    /// their own.
+    pub fn with_default_format(mut self, format: Format) -> Self {
+        self.default_format = format;
+        self
+    }
+
+    /// Restricts the files `img()` cells may read, or serves the images
+    /// from memory.
+    pub fn with_assets(mut self, assets: AssetResolver) -> Self {
+        self.assets = assets;
+        self
+    }
+
     pub fn save(&mut self, path: &str) -> Result<(), XlsxError> {
+        self.check_pending_freeze()?;
         if let Some(sheet) = self.worksheet.take() {
             // println!("pushing worksheet: {:?}", sheet.name());
             self.workbook.push_worksheet(sheet);
         }
-        self.workbook.save(path)
+        match &self.workbook_protection {
+            None => self.workbook.save(path),
+            Some(password) => {
+                let buffer = self.workbook.save_to_buffer()?;
+                let buffer = protect_workbook_structure(&buffer, password.as_deref())?;
+                std::fs::write(path, buffer)?;
+                Ok(())
+            }
+        }
     }
 
-    pub fn process_internal(&mut self, item: &Element) -> Result<(), XlsxError> {
+    pub fn process_internal(&mut self, item: &Element, layout: &Layout) -> Result<(), XlsxError> {
         // println!("processing item {:?}", item);
         match item {
             Element::Sheet(sheet) => {
-                let sheet_name = &sheet.name;
+                self.check_pending_freeze()?;
                 if let Some(sheet) = self.worksheet.take() {
                     self.workbook.push_worksheet(sheet);
                 }
-                let mut sheet = Worksheet::new();
-                sheet.set_name(sheet_name)?;
-                self.worksheet = Some(sheet);
-                self.row = 0;
-                self.col = 0;
-            }
-            Element::Row(row) => {
-                self.process_row(row)?;
+                let mut worksheet = Worksheet::new();
+                worksheet.set_name(&sheet.name)?;
+                self.worksheet = Some(worksheet);
+                self.page_breaks.clear();
+                self.column_formats.clear();
+                self.row_formats.clear();
+                self.written_cells.clear();
+                self.process_sheet_options(sheet)?;
             }
             Element::Anchor(anchor) => {
-                let (row, col) = (self.row, self.col);
-                self.anchors
-                    .insert(EcoString::from(anchor.identifier), (row, col));
+                self.process_anchor(anchor, layout)?;
             }
-            Element::Format(format) => {
-                self.process_format(format)?;
+            Element::Properties(properties) => {
+                self.process_properties(properties)?;
+            }
+            Element::Defaults(defaults) => {
+                self.process_defaults(defaults)?;
+            }
+            Element::AutoFilter(autofilter) => {
+                self.process_autofilter(autofilter, layout)?;
+            }
+            Element::PageSetup(page_setup) => {
+                self.process_page_setup(page_setup, layout)?;
+            }
+            Element::BorderRange(border_range) => {
+                self.process_border_range(border_range, layout)?;
+            }
+            Element::FillRange(fill_range) => {
+                self.process_fill_range(fill_range, layout)?;
             }
-            Element::Mover(mover) => {
-                if let Some(anchor) = mover.anchor {
-                    if let Some((a_row, a_col)) = self.anchors.get(anchor) {
-                        self.row = a_row.checked_add_signed(mover.row).unwrap_or_default();
-                        self.col = a_col.checked_add_signed(mover.col).unwrap_or_default();
+            Element::DefinedName(defined_name) => {
+                self.process_defined_name(defined_name, layout)?;
+            }
+            Element::Protect(protect) => {
+                self.process_protect(protect)?;
+            }
+            Element::ProtectWorkbook(protect) => {
+                let password = protect.password.as_ref().map(Expr::as_str);
+                self.workbook_protection = Some(password);
+            }
+            Element::PageBreak(_) => {
+                if let Some(sheet) = self.worksheet.as_mut() {
+                    if layout.row > 0 && !self.page_breaks.contains(&layout.row) {
+                        self.page_breaks.push(layout.row);
+                        sheet.set_page_breaks(&self.page_breaks)?;
                     }
-                } else {
-                    self.row = self.row.checked_add_signed(mover.row).unwrap_or_default();
-                    self.col = self.col.checked_add_signed(mover.col).unwrap_or_default();
                 }
             }
-            Element::Cr(_) => {
-                self.row += 1;
-                self.col = 0;
+            Element::Row(row) => {
+                self.process_row(row, layout)?;
+            }
+            Element::Format(format) => {
+                self.process_format(format)?;
+            }
+            Element::Conditional(conditional) => {
+                self.process_conditional(conditional, layout)?;
+            }
+            Element::Validation(validation) => {
+                self.process_validation(validation, layout)?;
+            }
+            Element::Chart(chart) => {
+                self.process_chart(chart, layout)?;
+            }
+            Element::Table(table) => {
+                self.process_table(table, layout)?;
             }
             Element::Autofit(_) => {
                 if let Some(sheet) = self.worksheet.as_mut() {
@@ -91,78 +242,547 @@ impl XlsxWriter {
                 }
             }
             Element::Column(column) => {
-                if let Some(sheet) = self.worksheet.as_mut() {
-                    if column.unit == "chars" {
-                        sheet.set_column_range_width(column.start, column.end, column.width)?;
-                    } else {
-                        sheet.set_column_range_width_pixels(
-                            column.start,
-                            column.end,
-                            column.width as u16,
-                        )?;
+                self.process_column(column)?;
+            }
+            Element::RowSpec(rowspec) => {
+                self.process_rowspec(rowspec)?;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    pub fn process_column(&mut self, column: &Column) -> Result<(), XlsxError> {
+        let format = self.line_format(column.format)?;
+        if let Some(sheet) = self.worksheet.as_mut() {
+            match column.unit {
+                "chars" => {
+                    sheet.set_column_range_width(column.start, column.end, column.width)?;
+                }
+                "pixels" => {
+                    sheet.set_column_range_width_pixels(
+                        column.start,
+                        column.end,
+                        column.width as u16,
+                    )?;
+                }
+                _ => {}
+            }
+            if let Some(format) = &format {
+                sheet.set_column_range_format(column.start, column.end, format)?;
+            }
+            if column.hidden {
+                sheet.set_column_range_hidden(column.start, column.end)?;
+            }
+            if let Some(name) = column.format {
+                for col in column.start..=column.end {
+                    self.column_formats.insert(col, EcoString::from(name));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn process_rowspec(&mut self, rowspec: &RowSpec) -> Result<(), XlsxError> {
+        let format = self.line_format(rowspec.format)?;
+        if let Some(sheet) = self.worksheet.as_mut() {
+            match rowspec.unit {
+                "chars" => {
+                    sheet.set_row_height(rowspec.start, rowspec.height)?;
+                }
+                "pixels" => {
+                    sheet.set_row_height_pixels(rowspec.start, rowspec.height as u16)?;
+                }
+                _ => {}
+            }
+            if let Some(format) = &format {
+                sheet.set_row_format(rowspec.start, format)?;
+            }
+            if rowspec.hidden {
+                sheet.set_row_hidden(rowspec.start)?;
+            }
+            if let Some(name) = rowspec.format {
+                self.row_formats
+                    .insert(rowspec.start, EcoString::from(name));
+            }
+        }
+        Ok(())
+    }
+
+    /// The declared format of a `col()` or `row()`.
+    fn line_format(&self, name: Option<&str>) -> Result<Option<Format>, XlsxError> {
+        name.map(|name| {
+            self.formats
+                .get(name)
+                .cloned()
+                .ok_or_else(|| XlsxError::ParameterError(format!("Unknown format: {}", name)))
+        })
+        .transpose()
+    }
+
+    pub fn process_properties(&mut self, properties: &Properties) -> Result<(), XlsxError> {
+        let mut doc = DocProperties::new();
+        for property in &properties.properties {
+            let args = &property.args;
+            let first = args.first().ok_or_else(|| argument_count(property.kind))?;
+            let text = first.as_str();
+            doc = match property.kind {
+                "title" => doc.set_title(text),
+                "subject" => doc.set_subject(text),
+                "author" => doc.set_author(text),
+                "manager" => doc.set_manager(text),
+                "company" => doc.set_company(text),
+                "category" => doc.set_category(text),
+                "keywords" => {
+                    let keywords: Vec<String> = args.iter().map(Expr::as_str).collect();
+                    doc.set_keywords(keywords.join(", "))
+                }
+                "comment" => doc.set_comment(text),
+                "status" => doc.set_status(text),
+                "hyperlink_base" => doc.set_hyperlink_base(text),
+                "custom" => {
+                    let value = args.get(1).ok_or_else(|| argument_count(property.kind))?;
+                    match value.as_value() {
+                        Some(Value::Integer(n)) => match i32::try_from(*n) {
+                            Ok(n) => doc.set_custom_property(text, n),
+                            Err(_) => doc.set_custom_property(text, *n as f64),
+                        },
+                        Some(Value::Float(n)) => doc.set_custom_property(text, *n),
+                        Some(Value::Boolean(b)) => doc.set_custom_property(text, *b),
+                        _ => doc.set_custom_property(text, value.as_str()),
                     }
                 }
+                _ => doc,
+            };
+        }
+        self.workbook.set_properties(&doc);
+        Ok(())
+    }
+
+    /// Replaces the default formats with declared ones. The formats must be
+    /// declared before the `defaults` block.
+    pub fn process_defaults(&mut self, defaults: &Defaults) -> Result<(), XlsxError> {
+        for default in &defaults.formats {
+            let format = self
+                .formats
+                .get(default.format)
+                .ok_or_else(|| {
+                    XlsxError::ParameterError(format!("Unknown format: {}", default.format))
+                })?
+                .clone();
+            match default.kind {
+                "num" => self.number_format = format,
+                "date" => self.date_format = format,
+                _ => self.default_format = format,
             }
-            Element::RowSpec(rowspec) => {
-                if let Some(sheet) = self.worksheet.as_mut() {
-                    if rowspec.unit == "chars" {
-                        sheet.set_row_height(rowspec.start, rowspec.height)?;
-                    } else {
-                        sheet.set_row_height_pixels(rowspec.start, rowspec.height as u16)?;
+        }
+        Ok(())
+    }
+
+    pub fn process_sheet_options(&mut self, sheet: &Sheet) -> Result<(), XlsxError> {
+        let Some(worksheet) = self.worksheet.as_mut() else {
+            return Ok(());
+        };
+        for option in &sheet.options {
+            match option {
+                SheetOption::Freeze(cell_ref) => {
+                    self.pending_freeze = Some((
+                        EcoString::from(cell_ref.anchor.as_str()),
+                        cell_ref.row,
+                        cell_ref.col,
+                    ));
+                }
+                SheetOption::Setting(kind, arg) => {
+                    let enable = arg.as_ref().is_none_or(Expr::as_bool);
+                    match *kind {
+                        "zoom" => {
+                            let zoom = arg.as_ref().ok_or_else(|| argument_count(kind))?;
+                            worksheet.set_zoom(sheet_zoom(zoom)?);
+                        }
+                        "gridlines" => {
+                            worksheet.set_screen_gridlines(enable);
+                        }
+                        "tab_color" => {
+                            let color = arg.as_ref().ok_or_else(|| argument_count(kind))?;
+                            worksheet.set_tab_color(range_color(&color.as_str())?);
+                        }
+                        "hidden" => {
+                            worksheet.set_hidden(enable);
+                        }
+                        "active" => {
+                            worksheet.set_active(enable);
+                        }
+                        "right_to_left" => {
+                            worksheet.set_right_to_left(enable);
+                        }
+                        _ => {}
                     }
                 }
             }
-            _ => {}
         }
+        Ok(())
+    }
+
+    /// Freezes the panes once the anchor named in `freeze()` is set on the
+    /// sheet, as it usually follows the header rows.
+    pub fn process_anchor(&mut self, anchor: &Anchor, layout: &Layout) -> Result<(), XlsxError> {
+        let Some(worksheet) = self.worksheet.as_mut() else {
+            return Ok(());
+        };
+        let Some((name, row, col)) = &self.pending_freeze else {
+            return Ok(());
+        };
+        if name.as_str() != anchor.name.as_str() {
+            return Ok(());
+        }
+        let row = layout
+            .row
+            .checked_add_signed(*row)
+            .ok_or_else(unknown_range)?;
+        let col = layout
+            .col
+            .checked_add_signed(*col)
+            .ok_or_else(unknown_range)?;
+        worksheet.set_freeze_panes(row, col)?;
+        self.pending_freeze = None;
+        Ok(())
+    }
+
+    fn check_pending_freeze(&mut self) -> Result<(), XlsxError> {
+        match self.pending_freeze.take() {
+            Some((name, _, _)) => Err(XlsxError::ParameterError(format!(
+                "The anchor of freeze() is not set on the sheet: {}",
+                name
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds the filter buttons to a range. Rows failing the column filters
+    /// are hidden when the workbook is saved, as Excel would do.
+    pub fn process_autofilter(
+        &mut self,
+        autofilter: &AutoFilter,
+        layout: &Layout,
+    ) -> Result<(), XlsxError> {
+        let Some(sheet) = self.worksheet.as_mut() else {
+            return Ok(());
+        };
+        let (first_row, first_col, last_row, last_col) = sheet_range(&autofilter.range, layout)?;
+        sheet.autofilter(first_row, first_col, last_row, last_col)?;
+
+        for column in &autofilter.columns {
+            let mut condition = FilterCondition::new();
+            match &column.rule {
+                FilterRule::List(values) => {
+                    for value in values {
+                        condition = match value.as_value() {
+                            Some(Value::Integer(_) | Value::Float(_)) => {
+                                condition.add_list_filter(value.as_f64())
+                            }
+                            _ => condition.add_list_filter(value.as_str().as_str()),
+                        };
+                    }
+                }
+                FilterRule::Custom { tests, or } => {
+                    for test in tests {
+                        let (criteria, value) = match test {
+                            FilterTest::Compare(op, value) => {
+                                let criteria = match op {
+                                    CompareOp::Eq => FilterCriteria::EqualTo,
+                                    CompareOp::Neq => FilterCriteria::NotEqualTo,
+                                    CompareOp::Lt => FilterCriteria::LessThan,
+                                    CompareOp::Lte => FilterCriteria::LessThanOrEqualTo,
+                                    CompareOp::Gt => FilterCriteria::GreaterThan,
+                                    CompareOp::Gte => FilterCriteria::GreaterThanOrEqualTo,
+                                };
+                                (criteria, value)
+                            }
+                            FilterTest::Text(op, value) => {
+                                let criteria = match *op {
+                                    "not_contains" => FilterCriteria::DoesNotContain,
+                                    "begins_with" => FilterCriteria::BeginsWith,
+                                    "ends_with" => FilterCriteria::EndsWith,
+                                    _ => FilterCriteria::Contains,
+                                };
+                                (criteria, value)
+                            }
+                        };
+                        condition = match value.as_value() {
+                            Some(Value::Integer(_) | Value::Float(_)) => {
+                                condition.add_custom_filter(criteria, value.as_f64())
+                            }
+                            _ => condition.add_custom_filter(criteria, value.as_str().as_str()),
+                        };
+                    }
+                    if *or {
+                        condition = condition.add_custom_boolean_or();
+                    }
+                }
+            }
+            let col = first_col
+                .checked_add(column.column)
+                .filter(|col| *col <= last_col)
+                .ok_or_else(|| {
+                    XlsxError::ParameterError(format!(
+                        "Filter column {} is outside of the range",
+                        column.column
+                    ))
+                })?;
+            sheet.filter_column(col, &condition)?;
+        }
+        Ok(())
+    }
+
+    /// Defines a name for a range, on the sheet the anchors were set on.
+    pub fn process_defined_name(
+        &mut self,
+        defined_name: &DefinedName,
+        layout: &Layout,
+    ) -> Result<(), XlsxError> {
+        let (first_row, first_col, last_row, last_col) = layout
+            .range(&defined_name.range)
+            .ok_or_else(unknown_range)?;
+        let sheet = quote_sheet_name(range_sheet(&defined_name.range, layout)?);
+        let name = defined_name.name.as_str();
+        if name.is_empty() {
+            return Err(XlsxError::ParameterError(
+                "Defined names cannot be empty".to_string(),
+            ));
+        }
+        let range = if (first_row, first_col) == (last_row, last_col) {
+            row_col_to_cell_absolute(first_row, first_col)
+        } else {
+            cell_range_absolute(first_row, first_col, last_row, last_col)
+        };
+        let formula = format!("={}!{}", sheet, range);
+        if defined_name.local {
+            self.workbook
+                .define_name(format!("{}!{}", sheet, name), &formula)?;
+        } else {
+            self.workbook.define_name(name, &formula)?;
+        }
+        Ok(())
+    }
+
+    pub fn process_protect(&mut self, protect: &Protect) -> Result<(), XlsxError> {
+        let Some(sheet) = self.worksheet.as_mut() else {
+            return Ok(());
+        };
+        if let Some(password) = &protect.password {
+            sheet.protect_with_password(&password.as_str());
+        } else {
+            sheet.protect();
+        }
+        if let Some(allow) = &protect.allow {
+            let mut options = ProtectionOptions {
+                select_locked_cells: false,
+                select_unlocked_cells: false,
+                ..Default::default()
+            };
+            for option in allow {
+                let enabled = match *option {
+                    "select_locked" => &mut options.select_locked_cells,
+                    "select_unlocked" => &mut options.select_unlocked_cells,
+                    "format_cells" => &mut options.format_cells,
+                    "format_columns" => &mut options.format_columns,
+                    "format_rows" => &mut options.format_rows,
+                    "insert_columns" => &mut options.insert_columns,
+                    "insert_rows" => &mut options.insert_rows,
+                    "insert_links" => &mut options.insert_links,
+                    "delete_columns" => &mut options.delete_columns,
+                    "delete_rows" => &mut options.delete_rows,
+                    "sort" => &mut options.sort,
+                    "autofilter" => &mut options.use_autofilter,
+                    "pivot_tables" => &mut options.use_pivot_tables,
+                    "edit_scenarios" => &mut options.edit_scenarios,
+                    "edit_objects" => &mut options.edit_objects,
+                    _ => {
+                        return Err(XlsxError::ParameterError(format!(
+                            "Unknown protection option: {}",
+                            option
+                        )))
+                    }
+                };
+                *enabled = true;
+            }
+            sheet.protect_with_options(&options);
+        }
+        Ok(())
+    }
 
+    pub fn process_page_setup(
+        &mut self,
+        page_setup: &PageSetup,
+        layout: &Layout,
+    ) -> Result<(), XlsxError> {
+        let Some(sheet) = self.worksheet.as_mut() else {
+            return Ok(());
+        };
+        for rule in &page_setup.rules {
+            match rule {
+                PageRule::Range(kind, range) => {
+                    let (first_row, first_col, last_row, last_col) = sheet_range(range, layout)?;
+                    match *kind {
+                        "print_area" => {
+                            sheet.set_print_area(first_row, first_col, last_row, last_col)?;
+                        }
+                        "repeat_rows" => {
+                            sheet.set_repeat_rows(first_row, last_row)?;
+                        }
+                        "repeat_columns" => {
+                            sheet.set_repeat_columns(first_col, last_col)?;
+                        }
+                        _ => {}
+                    }
+                }
+                PageRule::Setting(kind, args) => {
+                    let number = |i: usize| args.get(i).map(Expr::as_f64);
+                    let enable = args.first().is_none_or(Expr::as_bool);
+                    match *kind {
+                        "landscape" => {
+                            sheet.set_landscape();
+                        }
+                        "portrait" => {
+                            sheet.set_portrait();
+                        }
+                        "paper" => {
+                            let paper = args.first().ok_or_else(|| argument_count(kind))?;
+                            sheet.set_paper_size(interpret_paper_size(paper)?);
+                        }
+                        "margins" => {
+                            if args.len() < 4 {
+                                return Err(argument_count(kind));
+                            }
+                            // Negative margins keep the Excel defaults.
+                            let margin = |i: usize| number(i).unwrap_or(-1.0);
+                            sheet.set_margins(
+                                margin(0),
+                                margin(1),
+                                margin(2),
+                                margin(3),
+                                margin(4),
+                                margin(5),
+                            );
+                        }
+                        "fit_to_pages" => {
+                            let (Some(width), Some(height)) = (number(0), number(1)) else {
+                                return Err(argument_count(kind));
+                            };
+                            sheet.set_print_fit_to_pages(width as u16, height as u16);
+                        }
+                        "scale" => {
+                            let scale = number(0).ok_or_else(|| argument_count(kind))?;
+                            sheet.set_print_scale(scale as u16);
+                        }
+                        "header" => {
+                            let header = args.first().ok_or_else(|| argument_count(kind))?;
+                            sheet.set_header(header.as_str());
+                        }
+                        "footer" => {
+                            let footer = args.first().ok_or_else(|| argument_count(kind))?;
+                            sheet.set_footer(footer.as_str());
+                        }
+                        "center_horizontally" => {
+                            sheet.set_print_center_horizontally(enable);
+                        }
+                        "center_vertically" => {
+                            sheet.set_print_center_vertically(enable);
+                        }
+                        "print_gridlines" => {
+                            sheet.set_print_gridlines(enable);
+                        }
+                        "first_page_number" => {
+                            let first = number(0).ok_or_else(|| argument_count(kind))?;
+                            sheet.set_print_first_page_number(first as u16);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
         Ok(())
     }
 
-    pub fn process_row(&mut self, row: &Row) -> Result<(), XlsxError> {
-        if self.worksheet.is_some() {
-            let sheet = self.worksheet.as_mut().unwrap();
-            let save_col = self.col;
-            for item in &row.cells {
-                let cell = match item {
-                    RowItem::Cell(cell) => cell,
-                    RowItem::ForEachCell(_) => continue,
-                    RowItem::ForEachHeader(_) => continue,
+    pub fn process_row(&mut self, row: &Row, layout: &Layout) -> Result<(), XlsxError> {
+        // The styles of the cells, grouped by row before they are recorded.
+        let mut written = Vec::new();
+        let mut spanned = Vec::new();
+        if let Some(sheet) = self.worksheet.as_mut() {
+            for placed in layout.place(row) {
+                let cell = placed.cell;
+                let (row_idx, col) = (placed.row, placed.col);
+
+                let quoted;
+                // A cell without a format of its own takes the one of its row
+                // or column, then the default of its type. Numbers and dates
+                // only take line formats with a pattern, to keep their own.
+                let typed = matches!(cell.cell_type, CellType::Num | CellType::Date);
+                let name = cell.format.or_else(|| {
+                    [
+                        self.row_formats.get(&row_idx),
+                        self.column_formats.get(&col),
+                    ]
+                    .into_iter()
+                    .flatten()
+                    .find(|name| !typed || self.patterned_formats.contains(*name))
+                    .map(EcoString::as_str)
+                });
+                let style = match name.and_then(|f| self.formats.get_index_of(f)) {
+                    Some(index) => CellStyle::Declared(index),
+                    None => match cell.cell_type {
+                        CellType::Num => CellStyle::Number,
+                        CellType::Date => CellStyle::Date,
+                        _ => CellStyle::Text,
+                    },
+                };
+                let format = match style {
+                    CellStyle::Declared(index) => &self.formats[index],
+                    CellStyle::Number => &self.number_format,
+                    CellStyle::Date => &self.date_format,
+                    _ => &self.default_format,
                 };
 
-                let format = if let Some(f) = cell.format {
-                    if let Some(f) = self.formats.get(f) {
-                        f
-                    } else {
-                        match cell.cell_type {
-                            CellType::Num => &self.number_format,
-                            CellType::Date => &self.date_format,
-                            _ => &self.default_format,
+                // Every cell written as text is protected, including dates
+                // that are not valid and are written as they are.
+                let quote = self.protect_formulas
+                    && match cell.cell_type {
+                        CellType::Str | CellType::Rich | CellType::Bool => true,
+                        CellType::Date => {
+                            ExcelDateTime::parse_from_str(&cell.value.as_str()).is_err()
                         }
+                        CellType::Num | CellType::Url | CellType::Image => false,
                     }
+                    && is_formula_like(&cell.value.as_str());
+                let format = if quote {
+                    quoted = format.clone().set_quote_prefix();
+                    &quoted
                 } else {
-                    match cell.cell_type {
-                        CellType::Num => &self.number_format,
-                        CellType::Date => &self.date_format,
-                        _ => &self.default_format,
-                    }
+                    format
                 };
 
-                if cell.colspan > 1 || cell.rowspan > 1 {
-                    let end_row = self.row + cell.rowspan as u32 - 1;
-                    let end_col = self.col + cell.colspan - 1;
+                if placed.is_merged() {
                     match cell.cell_type {
                         CellType::Str => {
                             sheet.merge_range(
-                                self.row,
-                                self.col,
-                                end_row,
-                                end_col,
+                                row_idx,
+                                col,
+                                placed.end_row,
+                                placed.end_col,
                                 &cell.value.as_str(),
                                 format,
                             )?;
                         }
                         _ => {
-                            sheet.merge_range(self.row, self.col, end_row, end_col, "", format)?;
+                            sheet.merge_range(
+                                row_idx,
+                                col,
+                                placed.end_row,
+                                placed.end_col,
+                                "",
+                                format,
+                            )?;
                         }
                     }
                 }
@@ -170,17 +790,17 @@ impl XlsxWriter {
                 match cell.cell_type {
                     CellType::Num => {
                         sheet.write_number_with_format(
-                            self.row,
-                            self.col,
+                            row_idx,
+                            col,
                             cell.value.as_f64(),
                             format,
                         )?;
                     }
                     CellType::Str => {
-                        if cell.colspan == 1 && cell.rowspan == 1 {
+                        if !placed.is_merged() {
                             sheet.write_string_with_format(
-                                self.row,
-                                self.col,
+                                row_idx,
+                                col,
                                 cell.value.as_str(),
                                 format,
                             )?;
@@ -188,46 +808,649 @@ impl XlsxWriter {
                     }
                     CellType::Date => match ExcelDateTime::parse_from_str(&cell.value.as_str()) {
                         Ok(date) => {
-                            sheet.write_with_format(self.row, self.col, date, format)?;
+                            sheet.write_with_format(row_idx, col, date, format)?;
                         }
                         Err(_e) => {
                             sheet.write_string_with_format(
-                                self.row,
-                                self.col,
+                                row_idx,
+                                col,
                                 cell.value.as_str(),
                                 format,
                             )?;
                         }
                     },
                     CellType::Image => {
-                        let image_mode = cell.image_mode.unwrap_or("embed");
-                        let image = Image::new(cell.value.as_str())?;
-                        match image_mode {
-                            "embed" => {
-                                sheet.insert_image_fit_to_cell(self.row, self.col, &image, true)?;
-                            }
-                            "insert" => {
-                                sheet.insert_image(self.row, self.col, &image)?;
-                            }
-                            _ => {}
+                        let placement = image_placement(cell, layout, &self.assets)?;
+                        if placement.fit {
+                            sheet.insert_image_fit_to_cell(
+                                row_idx,
+                                col,
+                                &placement.image,
+                                placement.keep_aspect,
+                            )?;
+                        } else {
+                            sheet.insert_image_with_offset(
+                                row_idx,
+                                col,
+                                &placement.image,
+                                placement.offset_x,
+                                placement.offset_y,
+                            )?;
                         }
                     }
                     CellType::Bool => {
                         sheet.write_string_with_format(
-                            self.row,
-                            self.col,
+                            row_idx,
+                            col,
                             cell.value.as_str(),
                             format,
                         )?;
                     }
+                    CellType::Rich => {
+                        // Excel rejects empty runs of text.
+                        let texts: Vec<(Option<&str>, String)> = cell
+                            .rich
+                            .iter()
+                            .map(|segment| (segment.format, segment.text.as_str()))
+                            .filter(|(_, text)| !text.is_empty())
+                            .collect();
+                        let mut segments = Vec::with_capacity(texts.len());
+                        for (segment_format, text) in &texts {
+                            let segment_format = match segment_format {
+                                Some(name) => self.formats.get(*name).ok_or_else(|| {
+                                    XlsxError::ParameterError(format!("Unknown format: {}", name))
+                                })?,
+                                None => &self.default_format,
+                            };
+                            segments.push((segment_format, text.as_str()));
+                        }
+                        if segments.is_empty() {
+                            sheet.write_string_with_format(row_idx, col, "", format)?;
+                        } else {
+                            sheet.write_rich_string_with_format(row_idx, col, &segments, format)?;
+                        }
+                    }
+                    CellType::Url => {
+                        let mut url = Url::new(link_target(&cell.value.as_str(), layout)?);
+                        if let Some(text) = &cell.text {
+                            url = url.set_text(text.as_str());
+                        }
+                        // Without a format of its own the link gets Excel's hyperlink style.
+                        if cell.format.is_some() {
+                            sheet.write_url_with_format(row_idx, col, url, format)?;
+                        } else {
+                            sheet.write_url(row_idx, col, url)?;
+                        }
+                    }
+                }
+
+                let style = match cell.cell_type {
+                    CellType::Image if !placed.is_merged() => None,
+                    CellType::Url if cell.format.is_none() => Some(CellStyle::Hyperlink),
+                    _ => Some(style),
+                };
+                if let Some(style) = style {
+                    let record = WrittenCell {
+                        first_col: col,
+                        last_col: placed.end_col,
+                        style,
+                        quoted: quote,
+                    };
+                    written.push(record);
+                    for r in row_idx + 1..=placed.end_row {
+                        spanned.push((r, record));
+                    }
+                }
+
+                if let Some(note) = &cell.note {
+                    let mut xlsx_note = Note::new(note.text.as_str()).set_visible(note.visible);
+                    if let Some(author) = &note.author {
+                        xlsx_note = xlsx_note.set_author(author.as_str());
+                    }
+                    sheet.insert_note(row_idx, col, &xlsx_note)?;
+                }
+            }
+        }
+
+        if !written.is_empty() {
+            self.written_cells
+                .entry(layout.row)
+                .or_default()
+                .extend(written);
+        }
+        for (r, record) in spanned {
+            self.written_cells.entry(r).or_default().push(record);
+        }
+
+        Ok(())
+    }
+
+    pub fn process_border_range(
+        &mut self,
+        border_range: &BorderRange,
+        layout: &Layout,
+    ) -> Result<(), XlsxError> {
+        let (first_row, first_col, last_row, last_col) = sheet_range(&border_range.range, layout)?;
+        let style = border_range.style.as_str();
+        let border = interpret_border(&style)
+            .ok_or_else(|| XlsxError::ParameterError(format!("Unknown border style: {}", style)))?;
+        let color = border_range
+            .color
+            .as_ref()
+            .map(|color| range_color(&color.as_str()))
+            .transpose()?;
+
+        for row in first_row..=last_row {
+            for col in first_col..=last_col {
+                let (top, bottom) = (row == first_row, row == last_row);
+                let (left, right) = (col == first_col, col == last_col);
+                if !(top || bottom || left || right) {
+                    continue;
+                }
+                self.update_cell_format(row, col, |mut f| {
+                    if top {
+                        f = f.set_border_top(border);
+                    }
+                    if bottom {
+                        f = f.set_border_bottom(border);
+                    }
+                    if left {
+                        f = f.set_border_left(border);
+                    }
+                    if right {
+                        f = f.set_border_right(border);
+                    }
+                    if let Some(color) = color {
+                        if top {
+                            f = f.set_border_top_color(color);
+                        }
+                        if bottom {
+                            f = f.set_border_bottom_color(color);
+                        }
+                        if left {
+                            f = f.set_border_left_color(color);
+                        }
+                        if right {
+                            f = f.set_border_right_color(color);
+                        }
+                    }
+                    f
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn process_fill_range(
+        &mut self,
+        fill_range: &FillRange,
+        layout: &Layout,
+    ) -> Result<(), XlsxError> {
+        let (first_row, first_col, last_row, last_col) = sheet_range(&fill_range.range, layout)?;
+        let color = range_color(&fill_range.color.as_str())?;
+        for row in first_row..=last_row {
+            for col in first_col..=last_col {
+                self.update_cell_format(row, col, |f| f.set_background_color(color))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes the format of a cell, starting from the one it was written
+    /// with, or else the one of its row or column.
+    fn update_cell_format(
+        &mut self,
+        row: u32,
+        col: u16,
+        update: impl FnOnce(Format) -> Format,
+    ) -> Result<(), XlsxError> {
+        if self.worksheet.is_none() {
+            return Ok(());
+        }
+        let written = self.written_cells.get(&row).and_then(|cells| {
+            cells
+                .iter()
+                .rev()
+                .find(|cell| (cell.first_col..=cell.last_col).contains(&col))
+        });
+        let current = match written {
+            Some(written) if written.quoted => self.style_format(written.style).set_quote_prefix(),
+            Some(written) => self.style_format(written.style),
+            None => self
+                .row_formats
+                .get(&row)
+                .or_else(|| self.column_formats.get(&col))
+                .and_then(|name| self.formats.get(name))
+                .cloned()
+                .unwrap_or_default(),
+        };
+        let format = update(current);
+        if let Some(sheet) = self.worksheet.as_mut() {
+            sheet.set_cell_format(row, col, &format)?;
+        }
+        let index = self.range_formats.insert_full(format).0;
+        self.written_cells
+            .entry(row)
+            .or_default()
+            .push(WrittenCell {
+                first_col: col,
+                last_col: col,
+                style: CellStyle::Range(index),
+                quoted: false,
+            });
+        Ok(())
+    }
+
+    /// The format of a recorded cell style.
+    fn style_format(&self, style: CellStyle) -> Format {
+        match style {
+            CellStyle::Declared(index) => self.formats[index].clone(),
+            CellStyle::Number => self.number_format.clone(),
+            CellStyle::Date => self.date_format.clone(),
+            CellStyle::Text => self.default_format.clone(),
+            CellStyle::Hyperlink => Format::new().set_hyperlink(),
+            CellStyle::Range(index) => self.range_formats[index].clone(),
+        }
+    }
+
+    pub fn process_conditional(
+        &mut self,
+        conditional: &Conditional,
+        layout: &Layout,
+    ) -> Result<(), XlsxError> {
+        let Some(sheet) = self.worksheet.as_mut() else {
+            return Ok(());
+        };
+        let (first_row, first_col, last_row, last_col) = sheet_range(&conditional.range, layout)?;
+
+        for rule in &conditional.rules {
+            match rule {
+                ConditionalRule::When(test, format_name) => {
+                    let format = self.formats.get(*format_name).cloned().ok_or_else(|| {
+                        XlsxError::ParameterError(format!("Unknown format: {}", format_name))
+                    })?;
+                    match test {
+                        ConditionTest::Cell(op, value) => {
+                            let conditional_format = match value.as_value() {
+                                Some(Value::Integer(_) | Value::Float(_)) => {
+                                    ConditionalFormatCell::new()
+                                        .set_rule(cell_rule(*op, value.as_f64()))
+                                }
+                                _ => ConditionalFormatCell::new()
+                                    .set_rule(cell_rule(*op, value.as_str().as_str())),
+                            };
+                            sheet.add_conditional_format(
+                                first_row,
+                                first_col,
+                                last_row,
+                                last_col,
+                                &conditional_format.set_format(format),
+                            )?;
+                        }
+                        ConditionTest::Between { negated, low, high } => {
+                            let (low, high) = (low.as_f64(), high.as_f64());
+                            let rule = if *negated {
+                                ConditionalFormatCellRule::NotBetween(low, high)
+                            } else {
+                                ConditionalFormatCellRule::Between(low, high)
+                            };
+                            sheet.add_conditional_format(
+                                first_row,
+                                first_col,
+                                last_row,
+                                last_col,
+                                &ConditionalFormatCell::new()
+                                    .set_rule(rule)
+                                    .set_format(format),
+                            )?;
+                        }
+                        ConditionTest::Text(op, value) => {
+                            let text = value.as_str();
+                            let rule = match *op {
+                                "not_contains" => ConditionalFormatTextRule::DoesNotContain(text),
+                                "begins_with" => ConditionalFormatTextRule::BeginsWith(text),
+                                "ends_with" => ConditionalFormatTextRule::EndsWith(text),
+                                _ => ConditionalFormatTextRule::Contains(text),
+                            };
+                            sheet.add_conditional_format(
+                                first_row,
+                                first_col,
+                                last_row,
+                                last_col,
+                                &ConditionalFormatText::new()
+                                    .set_rule(rule)
+                                    .set_format(format),
+                            )?;
+                        }
+                        ConditionTest::Top {
+                            bottom,
+                            rank,
+                            percent,
+                        } => {
+                            let rule = match (bottom, percent) {
+                                (false, false) => ConditionalFormatTopRule::Top(*rank),
+                                (false, true) => ConditionalFormatTopRule::TopPercent(*rank),
+                                (true, false) => ConditionalFormatTopRule::Bottom(*rank),
+                                (true, true) => ConditionalFormatTopRule::BottomPercent(*rank),
+                            };
+                            sheet.add_conditional_format(
+                                first_row,
+                                first_col,
+                                last_row,
+                                last_col,
+                                &ConditionalFormatTop::new()
+                                    .set_rule(rule)
+                                    .set_format(format),
+                            )?;
+                        }
+                        ConditionTest::Duplicate { unique } => {
+                            let mut conditional_format =
+                                ConditionalFormatDuplicate::new().set_format(format);
+                            if *unique {
+                                conditional_format = conditional_format.invert();
+                            }
+                            sheet.add_conditional_format(
+                                first_row,
+                                first_col,
+                                last_row,
+                                last_col,
+                                &conditional_format,
+                            )?;
+                        }
+                    }
+                }
+                ConditionalRule::DataBar(color) => {
+                    let mut data_bar = ConditionalFormatDataBar::new();
+                    if let Some(color) = color {
+                        data_bar = data_bar.set_fill_color(range_color(&color.as_str())?);
+                    }
+                    sheet.add_conditional_format(
+                        first_row, first_col, last_row, last_col, &data_bar,
+                    )?;
+                }
+                ConditionalRule::ColorScale2(colors) => {
+                    let mut scale = ConditionalFormat2ColorScale::new();
+                    match colors.as_slice() {
+                        [] => {}
+                        [min, max] => {
+                            scale = scale
+                                .set_minimum_color(range_color(&min.as_str())?)
+                                .set_maximum_color(range_color(&max.as_str())?);
+                        }
+                        _ => return Err(argument_count("color_scale_2")),
+                    }
+                    sheet
+                        .add_conditional_format(first_row, first_col, last_row, last_col, &scale)?;
+                }
+                ConditionalRule::ColorScale3(colors) => {
+                    let mut scale = ConditionalFormat3ColorScale::new();
+                    match colors.as_slice() {
+                        [] => {}
+                        [min, mid, max] => {
+                            scale = scale
+                                .set_minimum_color(range_color(&min.as_str())?)
+                                .set_midpoint_color(range_color(&mid.as_str())?)
+                                .set_maximum_color(range_color(&max.as_str())?);
+                        }
+                        _ => return Err(argument_count("color_scale_3")),
+                    }
+                    sheet
+                        .add_conditional_format(first_row, first_col, last_row, last_col, &scale)?;
+                }
+                ConditionalRule::IconSet(icons) => {
+                    let icons = icons.as_str();
+                    let icon_type = interpret_icon_type(&icons).ok_or_else(|| {
+                        XlsxError::ParameterError(format!("Unknown icon set: {}", icons))
+                    })?;
+                    sheet.add_conditional_format(
+                        first_row,
+                        first_col,
+                        last_row,
+                        last_col,
+                        &ConditionalFormatIconSet::new().set_icon_type(icon_type),
+                    )?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn process_validation(
+        &mut self,
+        validation: &Validation,
+        layout: &Layout,
+    ) -> Result<(), XlsxError> {
+        let Some(sheet) = self.worksheet.as_mut() else {
+            return Ok(());
+        };
+        let (first_row, first_col, last_row, last_col) = sheet_range(&validation.range, layout)?;
+
+        let mut data_validation = DataValidation::new();
+        for rule in &validation.rules {
+            let args = &rule.args;
+            data_validation = match rule.kind {
+                "list" => {
+                    let mut items = Vec::new();
+                    for arg in args {
+                        match arg.as_value() {
+                            Some(Value::Array(arr)) => items.extend(arr.iter().map(Value::as_str)),
+                            _ => items.push(arg.as_str()),
+                        }
+                    }
+                    data_validation.allow_list_strings(&items)?
+                }
+                "whole_number" => {
+                    data_validation.allow_whole_number(validation_bounds(rule.kind, args, |v| {
+                        v.as_f64() as i32
+                    })?)
+                }
+                "decimal" => {
+                    data_validation
+                        .allow_decimal_number(validation_bounds(rule.kind, args, |v| v.as_f64())?)
+                }
+                "text_length" => {
+                    data_validation.allow_text_length(validation_bounds(rule.kind, args, |v| {
+                        v.as_f64() as u32
+                    })?)
+                }
+                "date" => {
+                    let mut dates = Vec::new();
+                    for arg in args {
+                        dates.push(ExcelDateTime::parse_from_str(&arg.as_str())?);
+                    }
+                    let rule = match dates.as_slice() {
+                        [from] => DataValidationRule::GreaterThanOrEqualTo(from.clone()),
+                        [from, to] => DataValidationRule::Between(from.clone(), to.clone()),
+                        _ => return Err(argument_count(rule.kind)),
+                    };
+                    data_validation.allow_date(rule)
+                }
+                "custom" => {
+                    let formula = args.first().ok_or_else(|| argument_count(rule.kind))?;
+                    data_validation.allow_custom(Formula::new(formula.as_str()))
+                }
+                "input" => {
+                    let (title, message) = validation_texts(rule.kind, args)?;
+                    data_validation
+                        .set_input_title(title)?
+                        .set_input_message(message)?
+                }
+                "error" => {
+                    let (title, message) = validation_texts(rule.kind, args)?;
+                    let style = match args.get(2).map(Expr::as_str).as_deref() {
+                        None | Some("stop") => DataValidationErrorStyle::Stop,
+                        Some("warning") => DataValidationErrorStyle::Warning,
+                        Some("information") => DataValidationErrorStyle::Information,
+                        Some(other) => {
+                            return Err(XlsxError::ParameterError(format!(
+                                "Unknown error style: {}",
+                                other
+                            )))
+                        }
+                    };
+                    data_validation
+                        .set_error_title(title)?
+                        .set_error_message(message)?
+                        .set_error_style(style)
                 }
-                self.col += cell.colspan;
+                _ => data_validation,
+            };
+        }
+
+        sheet.add_data_validation(first_row, first_col, last_row, last_col, &data_validation)?;
+        Ok(())
+    }
+
+    pub fn process_chart(
+        &mut self,
+        element: &ChartElement,
+        layout: &Layout,
+    ) -> Result<(), XlsxError> {
+        let Some(sheet) = self.worksheet.as_mut() else {
+            return Ok(());
+        };
+        let (row, col) = match &element.position {
+            Some(position) => layout.cell_ref(position).ok_or_else(unknown_range)?,
+            None => (layout.row, layout.col),
+        };
+
+        let mut chart = match element.kind {
+            "column" => Chart::new_column(),
+            "bar" => Chart::new_bar(),
+            "line" => Chart::new_line(),
+            "pie" => Chart::new_pie(),
+            "scatter" => Chart::new_scatter(),
+            "area" => Chart::new_area(),
+            kind => {
+                return Err(XlsxError::ParameterError(format!(
+                    "Unknown chart type: {}",
+                    kind
+                )))
             }
+        };
 
-            self.row += 1;
-            self.col = save_col;
+        // The ranges are on the sheets their anchors were set on, which may
+        // not be the sheet of the chart.
+        for s in &element.series {
+            let (first_row, first_col, last_row, last_col) =
+                layout.range(&s.values).ok_or_else(unknown_range)?;
+            let series = chart.add_series();
+            series.set_values((
+                range_sheet(&s.values, layout)?,
+                first_row,
+                first_col,
+                last_row,
+                last_col,
+            ));
+            if let Some(categories) = &s.categories {
+                let (first_row, first_col, last_row, last_col) =
+                    layout.range(categories).ok_or_else(unknown_range)?;
+                series.set_categories((
+                    range_sheet(categories, layout)?,
+                    first_row,
+                    first_col,
+                    last_row,
+                    last_col,
+                ));
+            }
+            if let Some(name) = &s.name {
+                series.set_name(name.as_str().as_str());
+            }
+        }
+
+        for option in &element.options {
+            let args = &option.args;
+            let first = args.first().ok_or_else(|| argument_count(option.kind))?;
+            match option.kind {
+                "title" => {
+                    chart.title().set_name(first.as_str().as_str());
+                }
+                "x_axis" => {
+                    chart.x_axis().set_name(first.as_str().as_str());
+                }
+                "y_axis" => {
+                    chart.y_axis().set_name(first.as_str().as_str());
+                }
+                "legend" => {
+                    let position = match first.as_str().as_str() {
+                        "right" => ChartLegendPosition::Right,
+                        "left" => ChartLegendPosition::Left,
+                        "top" => ChartLegendPosition::Top,
+                        "bottom" => ChartLegendPosition::Bottom,
+                        "top_right" => ChartLegendPosition::TopRight,
+                        "none" => {
+                            chart.legend().set_hidden();
+                            continue;
+                        }
+                        other => {
+                            return Err(XlsxError::ParameterError(format!(
+                                "Unknown legend position: {}",
+                                other
+                            )))
+                        }
+                    };
+                    chart.legend().set_position(position);
+                }
+                "size" => {
+                    let height = args.get(1).ok_or_else(|| argument_count(option.kind))?;
+                    chart.set_width(first.as_f64() as u32);
+                    chart.set_height(height.as_f64() as u32);
+                }
+                "style" => {
+                    chart.set_style(first.as_f64() as u8);
+                }
+                _ => {}
+            }
         }
 
+        sheet.insert_chart(row, col, &chart)?;
+        Ok(())
+    }
+
+    /// Registers the rows written for a `table` block as an Excel table.
+    ///
+    /// The header cells are rewritten by the table, so their text and
+    /// format are passed on to the table columns.
+    pub fn process_table(
+        &mut self,
+        element: &TableElement,
+        layout: &Layout,
+    ) -> Result<(), XlsxError> {
+        let Some(sheet) = self.worksheet.as_mut() else {
+            return Ok(());
+        };
+        let Some((first_row, first_col, last_row, last_col)) = element.region else {
+            return Ok(());
+        };
+
+        let mut columns = Vec::new();
+        for placed in layout.place(&element.header) {
+            let mut column = TableColumn::new().set_header(placed.cell.value.as_str());
+            if let Some(format) = placed.cell.format.and_then(|f| self.formats.get(f)) {
+                column = column.set_header_format(format);
+            }
+            columns.push(column);
+        }
+        columns.resize_with((last_col - first_col + 1) as usize, TableColumn::new);
+        for (column, item) in columns.iter_mut().zip(&element.totals) {
+            *column = match item {
+                TotalsItem::Label(label) => column.clone().set_total_label(label.as_str()),
+                TotalsItem::Function(function) => column
+                    .clone()
+                    .set_total_function(interpret_table_function(function)?),
+            };
+        }
+
+        let mut table = Table::new()
+            .set_name(element.name.as_str())
+            .set_columns(&columns)
+            .set_total_row(!element.totals.is_empty());
+        if let Some(style) = &element.style {
+            table = table.set_style(interpret_table_style(&style.as_str())?);
+        }
+
+        sheet.add_table(first_row, first_col, last_row, last_col, &table)?;
         Ok(())
     }
 
@@ -236,6 +1459,13 @@ impl XlsxWriter {
         for modifier in &format.modifiers {
             let param_string = modifier.expression.as_str();
             let param = param_string.as_str();
+            if let Err(message) = check_modifier(format.identifier, modifier.statement, param) {
+                if self.strict_formats {
+                    return Err(XlsxError::ParameterError(message));
+                }
+                self.warnings.push(message);
+                continue;
+            }
 
             match modifier.statement {
                 "bold" => {
@@ -245,7 +1475,9 @@ impl XlsxWriter {
                     f = f.set_italic();
                 }
                 "underline" => {
-                    f = f.set_underline(FormatUnderline::Single);
+                    if let Some(underline) = interpret_underline(param) {
+                        f = f.set_underline(underline);
+                    }
                 }
                 "strikethrough" => {
                     f = f.set_font_strikethrough();
@@ -257,21 +1489,17 @@ impl XlsxWriter {
                     f = f.set_font_script(FormatScript::Subscript);
                 }
                 "color" => {
-                    f = f.set_font_color(param);
+                    if let Some(color) = interpret_color(param) {
+                        f = f.set_font_color(color);
+                    }
                 }
                 "num" => {
                     f = f.set_num_format(param);
                 }
                 "align" => {
-                    match param {
-                        "left" => f = f.set_align(FormatAlign::Left),
-                        "right" => f = f.set_align(FormatAlign::Right),
-                        "center" => f = f.set_align(FormatAlign::Center),
-                        "top" => f = f.set_align(FormatAlign::Top),
-                        "bottom" => f = f.set_align(FormatAlign::Bottom),
-                        "verticalcenter" => f = f.set_align(FormatAlign::VerticalCenter),
-                        _ => {}
-                    };
+                    if let Some(align) = interpret_align(param) {
+                        f = f.set_align(align);
+                    }
                 }
                 "indent" => {
                     if let Ok(indent) = param.parse::<u8>() {
@@ -284,43 +1512,114 @@ impl XlsxWriter {
                         f = f.set_font_size(size);
                     }
                 }
-                "background_color" => f = f.set_background_color(param),
+                "font_family" => {
+                    if let Ok(family) = param.parse::<u8>() {
+                        f = f.set_font_family(family);
+                    }
+                }
+                "font_scheme" => f = f.set_font_scheme(param),
+                "font_charset" => {
+                    if let Ok(charset) = param.parse::<u8>() {
+                        f = f.set_font_charset(charset);
+                    }
+                }
+                "wrap" => f = f.set_text_wrap(),
+                "shrink" => f = f.set_shrink(),
+                "rotation" => {
+                    if let Ok(rotation) = param.parse::<i16>() {
+                        f = f.set_rotation(rotation);
+                    }
+                }
+                "pattern" => {
+                    if let Some(pattern) = interpret_pattern(param) {
+                        f = f.set_pattern(pattern);
+                    }
+                }
+                "background_color" => {
+                    if let Some(color) = interpret_color(param) {
+                        f = f.set_background_color(color);
+                    }
+                }
+                "foreground_color" => {
+                    if let Some(color) = interpret_color(param) {
+                        f = f.set_foreground_color(color);
+                    }
+                }
                 "border" => {
-                    let border = interpret_border(param);
-                    f = f.set_border(border);
+                    if let Some(border) = interpret_border(param) {
+                        f = f.set_border(border);
+                    }
                 }
                 "border_top" => {
-                    let border = interpret_border(param);
-                    f = f.set_border_top(border);
+                    if let Some(border) = interpret_border(param) {
+                        f = f.set_border_top(border);
+                    }
                 }
                 "border_bottom" => {
-                    let border = interpret_border(param);
-                    f = f.set_border_bottom(border);
+                    if let Some(border) = interpret_border(param) {
+                        f = f.set_border_bottom(border);
+                    }
                 }
                 "border_left" => {
-                    let border = interpret_border(param);
-                    f = f.set_border_left(border);
+                    if let Some(border) = interpret_border(param) {
+                        f = f.set_border_left(border);
+                    }
                 }
                 "border_right" => {
-                    let border = interpret_border(param);
-                    f = f.set_border_right(border);
-                }
-                "border_color" => f = f.set_border_color(param),
-                "border_top_color" => f = f.set_border_top_color(param),
-                "border_bottom_color" => f = f.set_border_bottom_color(param),
-                "border_left_color" => f = f.set_border_left_color(param),
-                "border_right_color" => f = f.set_border_right_color(param),
+                    if let Some(border) = interpret_border(param) {
+                        f = f.set_border_right(border);
+                    }
+                }
+                "diagonal_border" => {
+                    if let Some(border) = interpret_border(param) {
+                        f = f.set_border_diagonal(border);
+                    }
+                }
+                "diagonal_type" => {
+                    if let Some(diagonal) = interpret_diagonal(param) {
+                        f = f.set_border_diagonal_type(diagonal);
+                    }
+                }
+                "border_color"
+                | "border_top_color"
+                | "border_bottom_color"
+                | "border_left_color"
+                | "border_right_color"
+                | "diagonal_border_color" => {
+                    if let Some(color) = interpret_color(param) {
+                        f = match modifier.statement {
+                            "border_color" => f.set_border_color(color),
+                            "border_top_color" => f.set_border_top_color(color),
+                            "border_bottom_color" => f.set_border_bottom_color(color),
+                            "border_left_color" => f.set_border_left_color(color),
+                            "border_right_color" => f.set_border_right_color(color),
+                            _ => f.set_border_diagonal_color(color),
+                        };
+                    }
+                }
+                "locked" => f = f.set_locked(),
+                "unlocked" => f = f.set_unlocked(),
+                "hidden" => f = f.set_hidden(),
                 _ => {}
             }
         }
-        self.formats.insert(EcoString::from(format.identifier), f);
+        let identifier = EcoString::from(format.identifier);
+        let patterned = format.modifiers.iter().any(|modifier| {
+            modifier.statement == "num" && !modifier.expression.as_str().is_empty()
+        });
+        if patterned {
+            self.patterned_formats.insert(identifier.clone());
+        } else {
+            self.patterned_formats.remove(&identifier);
+        }
+        self.formats.insert(identifier, f);
         Ok(())
     }
 }
 
 impl SheetProcessor for XlsxWriter {
-    fn process(&mut self, item: &Element) -> Result<(), SpreadSheetError> {
-        self.process_internal(item).map_err(handle_error)
+    fn process(&mut self, item: &Element, layout: &Layout) -> Result<(), SpreadSheetError> {
+        self.process_internal(item, layout).map_err(handle_error)
     }
 }
 
@@ -329,22 +1628,1209 @@ fn handle_error(e: XlsxError) -> SpreadSheetError {
     SpreadSheetError::new(msg)
 }
 
-fn interpret_border(border: &str) -> FormatBorder {
-    match border {
-        "none" => rust_xlsxwriter::FormatBorder::None,
-        "thin" => rust_xlsxwriter::FormatBorder::Thin,
-        "medium" => rust_xlsxwriter::FormatBorder::Medium,
-        "dashed" => rust_xlsxwriter::FormatBorder::Dashed,
-        "dotted" => rust_xlsxwriter::FormatBorder::Dotted,
-        "thick" => rust_xlsxwriter::FormatBorder::Thick,
-        "double" => rust_xlsxwriter::FormatBorder::Double,
-        "hair" => rust_xlsxwriter::FormatBorder::Hair,
-        "medium_dashed" => rust_xlsxwriter::FormatBorder::MediumDashed,
-        "dash_dot" => rust_xlsxwriter::FormatBorder::DashDot,
-        "medium_dash_dot" => rust_xlsxwriter::FormatBorder::MediumDashDot,
-        "dash_dot_dot" => rust_xlsxwriter::FormatBorder::DashDotDot,
-        "medium_dash_dot_dot" => rust_xlsxwriter::FormatBorder::MediumDashDotDot,
-        "slant_dash_dot" => rust_xlsxwriter::FormatBorder::SlantDashDot,
-        _ => rust_xlsxwriter::FormatBorder::Thin,
+fn unknown_range() -> XlsxError {
+    XlsxError::ParameterError("Range refers to an unknown anchor".to_string())
+}
+
+/// The sheet the anchors of a range were set on.
+fn range_sheet<'l>(range: &Range, layout: &'l Layout) -> Result<&'l str, XlsxError> {
+    let sheet = |cell: &CellRef| {
+        layout
+            .anchor_position(&cell.anchor.as_str())
+            .map(|anchor| anchor.sheet.as_str())
+            .ok_or_else(unknown_range)
+    };
+    let from = sheet(&range.from)?;
+    match &range.to {
+        Some(to) if sheet(to)? != from => Err(XlsxError::ParameterError(
+            "A range cannot span several sheets".to_string(),
+        )),
+        _ => Ok(from),
+    }
+}
+
+/// The area of a range on the current sheet. The positions of anchors set
+/// on another sheet mean nothing here.
+fn sheet_range(range: &Range, layout: &Layout) -> Result<(u32, u16, u32, u16), XlsxError> {
+    if range_sheet(range, layout)? != layout.sheet.as_str() {
+        return Err(XlsxError::ParameterError(format!(
+            "Range refers to an anchor of another sheet: {}",
+            range.from.anchor.as_str()
+        )));
+    }
+    layout.range(range).ok_or_else(unknown_range)
+}
+
+/// The link of a `url()` cell. A target starting with `@` links to the
+/// cell of that anchor, on the sheet the anchor was set on.
+fn link_target(target: &str, layout: &Layout) -> Result<String, XlsxError> {
+    if !target.starts_with('@') {
+        return Ok(target.to_string());
+    }
+    let anchor = layout
+        .anchor_position(target)
+        .ok_or_else(|| XlsxError::ParameterError(format!("Unknown anchor: {}", target)))?;
+    Ok(format!(
+        "internal:{}!{}",
+        quote_sheet_name(&anchor.sheet),
+        row_col_to_cell(anchor.row, anchor.col)
+    ))
+}
+
+/// Quotes a sheet name for use in references, e.g. `'Sheet 1'`.
+fn quote_sheet_name(name: &str) -> String {
+    format!("'{}'", name.replace('\'', "''"))
+}
+
+/// Adds a `workbookProtection` element to the workbook part of a saved file,
+/// which rust_xlsxwriter cannot write itself.
+fn protect_workbook_structure(buffer: &[u8], password: Option<&str>) -> Result<Vec<u8>, XlsxError> {
+    let mut archive = ZipArchive::new(Cursor::new(buffer))?;
+    let mut output = ZipWriter::new(Cursor::new(Vec::new()));
+    let mut protected = false;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if file.name() != "xl/workbook.xml" {
+            output.raw_copy_file(file)?;
+            continue;
+        }
+        let mut xml = String::new();
+        file.read_to_string(&mut xml)?;
+        let protection = match password {
+            Some(password) => format!(
+                r#"<workbookProtection workbookPassword="{:04X}" lockStructure="1"/>"#,
+                hash_password(password)
+            ),
+            None => r#"<workbookProtection lockStructure="1"/>"#.to_string(),
+        };
+        // The element goes right before the book views.
+        let position = xml.find("<bookViews").ok_or_else(protection_failed)?;
+        xml.insert_str(position, &protection);
+        protected = true;
+        output.start_file(file.name(), SimpleFileOptions::default())?;
+        output.write_all(xml.as_bytes())?;
+    }
+    if !protected {
+        return Err(protection_failed());
+    }
+    Ok(output.finish()?.into_inner())
+}
+
+fn protection_failed() -> XlsxError {
+    XlsxError::ParameterError("Cannot protect the workbook structure".to_string())
+}
+
+/// The legacy password hash of Excel (ECMA-376-4, 18.2.29).
+fn hash_password(password: &str) -> u16 {
+    if password.is_empty() {
+        return 0;
+    }
+    let mut hash: u16 = 0;
+    for byte in password.bytes().rev() {
+        hash = ((hash >> 14) & 0x01) | ((hash << 1) & 0x7FFF);
+        hash ^= u16::from(byte);
+    }
+    hash = ((hash >> 14) & 0x01) | ((hash << 1) & 0x7FFF);
+    hash ^= password.len() as u16;
+    hash ^ 0xCE4B
+}
+
+/// The paper size index of Excel, given by number or by a common name.
+fn interpret_paper_size(paper: &Expr) -> Result<u8, XlsxError> {
+    if let Some(Value::Integer(index)) = paper.as_value() {
+        return Ok(*index as u8);
+    }
+    match paper.as_str().to_lowercase().as_str() {
+        "letter" => Ok(1),
+        "legal" => Ok(5),
+        "a3" => Ok(8),
+        "a4" => Ok(9),
+        "a5" => Ok(11),
+        other => Err(XlsxError::ParameterError(format!(
+            "Unknown paper size: {}",
+            other
+        ))),
+    }
+}
+
+fn interpret_table_function(function: &str) -> Result<TableFunction, XlsxError> {
+    Ok(match function {
+        "none" => TableFunction::None,
+        "sum" => TableFunction::Sum,
+        "average" => TableFunction::Average,
+        "count" => TableFunction::Count,
+        "count_numbers" => TableFunction::CountNumbers,
+        "max" => TableFunction::Max,
+        "min" => TableFunction::Min,
+        "std_dev" => TableFunction::StdDev,
+        "var" => TableFunction::Var,
+        _ => {
+            return Err(XlsxError::ParameterError(format!(
+                "Unknown total function: {}",
+                function
+            )))
+        }
+    })
+}
+
+const TABLE_STYLES: [TableStyle; 61] = [
+    TableStyle::None,
+    TableStyle::Light1,
+    TableStyle::Light2,
+    TableStyle::Light3,
+    TableStyle::Light4,
+    TableStyle::Light5,
+    TableStyle::Light6,
+    TableStyle::Light7,
+    TableStyle::Light8,
+    TableStyle::Light9,
+    TableStyle::Light10,
+    TableStyle::Light11,
+    TableStyle::Light12,
+    TableStyle::Light13,
+    TableStyle::Light14,
+    TableStyle::Light15,
+    TableStyle::Light16,
+    TableStyle::Light17,
+    TableStyle::Light18,
+    TableStyle::Light19,
+    TableStyle::Light20,
+    TableStyle::Light21,
+    TableStyle::Medium1,
+    TableStyle::Medium2,
+    TableStyle::Medium3,
+    TableStyle::Medium4,
+    TableStyle::Medium5,
+    TableStyle::Medium6,
+    TableStyle::Medium7,
+    TableStyle::Medium8,
+    TableStyle::Medium9,
+    TableStyle::Medium10,
+    TableStyle::Medium11,
+    TableStyle::Medium12,
+    TableStyle::Medium13,
+    TableStyle::Medium14,
+    TableStyle::Medium15,
+    TableStyle::Medium16,
+    TableStyle::Medium17,
+    TableStyle::Medium18,
+    TableStyle::Medium19,
+    TableStyle::Medium20,
+    TableStyle::Medium21,
+    TableStyle::Medium22,
+    TableStyle::Medium23,
+    TableStyle::Medium24,
+    TableStyle::Medium25,
+    TableStyle::Medium26,
+    TableStyle::Medium27,
+    TableStyle::Medium28,
+    TableStyle::Dark1,
+    TableStyle::Dark2,
+    TableStyle::Dark3,
+    TableStyle::Dark4,
+    TableStyle::Dark5,
+    TableStyle::Dark6,
+    TableStyle::Dark7,
+    TableStyle::Dark8,
+    TableStyle::Dark9,
+    TableStyle::Dark10,
+    TableStyle::Dark11,
+];
+
+/// Looks up a table style by name, e.g. `medium_2` for Table Style Medium 2.
+fn interpret_table_style(name: &str) -> Result<TableStyle, XlsxError> {
+    let wanted = format!("TableStyle{}", name.replace('_', ""));
+    TABLE_STYLES
+        .into_iter()
+        .find(|style| style.to_string().eq_ignore_ascii_case(&wanted))
+        .ok_or_else(|| XlsxError::ParameterError(format!("Unknown table style: {}", name)))
+}
+
+/// How the image of an `img()` cell is placed.
+struct ImagePlacement {
+    image: Image,
+    /// Scale the image to the cell, ignoring the offsets.
+    fit: bool,
+    keep_aspect: bool,
+    offset_x: u32,
+    offset_y: u32,
+}
+
+/// Loads the image of an `img()` cell and applies its options. The image
+/// is fitted to the cell in `embed` mode unless `fit: false` is given.
+fn image_placement(
+    cell: &Cell,
+    layout: &Layout,
+    assets: &AssetResolver,
+) -> Result<ImagePlacement, XlsxError> {
+    let base64 = cell
+        .image_options
+        .iter()
+        .any(|option| option.kind == "base64" && option.value.as_bool());
+    let mut placement = ImagePlacement {
+        image: load_image(&cell.value, base64, assets)?,
+        fit: cell.image_mode.unwrap_or("embed") == "embed",
+        keep_aspect: true,
+        offset_x: 0,
+        offset_y: 0,
+    };
+    let mut sized = None;
+    for option in &cell.image_options {
+        let value = &option.value;
+        if matches!(
+            option.kind,
+            "scale" | "scale_x" | "scale_y" | "offset_x" | "offset_y"
+        ) {
+            sized = Some(option.kind);
+        }
+        let image = placement.image;
+        placement.image = match option.kind {
+            "scale" => image
+                .set_scale_width(image_scale(value)?)
+                .set_scale_height(image_scale(value)?),
+            "scale_x" => image.set_scale_width(image_scale(value)?),
+            "scale_y" => image.set_scale_height(image_scale(value)?),
+            "offset_x" => {
+                placement.offset_x = image_offset(value)?;
+                image
+            }
+            "offset_y" => {
+                placement.offset_y = image_offset(value)?;
+                image
+            }
+            "fit" => {
+                placement.fit = value.as_bool();
+                image
+            }
+            "keep_aspect" => {
+                placement.keep_aspect = value.as_bool();
+                image
+            }
+            "alt" => image.set_alt_text(value.as_str()),
+            "decorative" => image.set_decorative(value.as_bool()),
+            "link" => image.set_url(link_target(&value.as_str(), layout)?.as_str())?,
+            _ => image,
+        };
+    }
+    // A fitted image takes the size of the cell and has no offset.
+    if let (true, Some(kind)) = (placement.fit, sized) {
+        return Err(XlsxError::ParameterError(format!(
+            "Image option {} needs the insert placement or fit: false",
+            kind
+        )));
+    }
+    Ok(placement)
+}
+
+/// The image of an `img()` cell: a path for the asset resolver, a `data:`
+/// URI, a base64 string with `base64: true`, or an array of bytes.
+fn load_image(value: &Expr, base64: bool, assets: &AssetResolver) -> Result<Image, XlsxError> {
+    if let Some(Value::Array(items)) = value.as_value() {
+        let bytes = items
+            .iter()
+            .map(|item| match item {
+                Value::Integer(byte) => u8::try_from(*byte).ok(),
+                _ => None,
+            })
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| {
+                XlsxError::ParameterError("Image bytes must be numbers from 0 to 255".to_string())
+            })?;
+        return Image::new_from_buffer(&bytes);
+    }
+    let source = value.as_str();
+    let encoded = match source.strip_prefix("data:") {
+        Some(uri) => {
+            let (_, data) = uri.split_once(";base64,").ok_or_else(|| {
+                XlsxError::ParameterError("Image data URIs must be base64 encoded".to_string())
+            })?;
+            Some(data)
+        }
+        None if base64 => Some(source.as_str()),
+        None => None,
+    };
+    match encoded {
+        Some(data) => Image::new_from_buffer(&decode_base64(data)?),
+        None => {
+            let data = assets
+                .resolve(&source)
+                .map_err(|e| XlsxError::ParameterError(e.to_string()))?;
+            Image::new_from_buffer(&data)
+        }
+    }
+}
+
+/// Decodes standard or URL-safe base64, with or without padding. The two
+/// alphabets cannot be mixed.
+fn decode_base64(data: &str) -> Result<Vec<u8>, XlsxError> {
+    let invalid = || XlsxError::ParameterError("Invalid base64 image data".to_string());
+    let data: Vec<u8> = data.bytes().filter(|c| !c.is_ascii_whitespace()).collect();
+    let padding = data.iter().rev().take_while(|c| **c == b'=').count();
+    let data = &data[..data.len() - padding];
+    if padding > 2
+        || (padding > 0 && !(data.len() + padding).is_multiple_of(4))
+        || data.len() % 4 == 1
+    {
+        return Err(invalid());
+    }
+    let standard = data.iter().any(|c| matches!(c, b'+' | b'/'));
+    let url_safe = data.iter().any(|c| matches!(c, b'-' | b'_'));
+    if standard && url_safe {
+        return Err(invalid());
+    }
+
+    let mut bytes = Vec::with_capacity(data.len() * 3 / 4);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &c in data {
+        let sextet = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return Err(invalid()),
+        };
+        buffer = (buffer << 6) | u32::from(sextet);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    // The bits left over after the last byte are zero in valid data.
+    if buffer != 0 {
+        return Err(invalid());
+    }
+    Ok(bytes)
+}
+
+fn image_scale(value: &Expr) -> Result<f64, XlsxError> {
+    let scale = value.as_f64();
+    if scale > 0.0 {
+        Ok(scale)
+    } else {
+        Err(XlsxError::ParameterError(format!(
+            "Invalid image scale: {}",
+            value.as_str()
+        )))
+    }
+}
+
+fn image_offset(value: &Expr) -> Result<u32, XlsxError> {
+    match value.as_value() {
+        Some(Value::Integer(offset)) => u32::try_from(*offset).ok(),
+        _ => None,
+    }
+    .ok_or_else(|| XlsxError::ParameterError(format!("Invalid image offset: {}", value.as_str())))
+}
+
+/// Excel zooms a sheet from 10 to 400 percent.
+fn sheet_zoom(value: &Expr) -> Result<u16, XlsxError> {
+    match value.as_value() {
+        Some(Value::Integer(zoom)) => u16::try_from(*zoom)
+            .ok()
+            .filter(|zoom| (10..=400).contains(zoom)),
+        _ => None,
+    }
+    .ok_or_else(|| XlsxError::ParameterError(format!("Invalid zoom: {}", value.as_str())))
+}
+
+fn range_color(color: &str) -> Result<Color, XlsxError> {
+    interpret_color(color)
+        .ok_or_else(|| XlsxError::ParameterError(format!("Invalid color: {}", color)))
+}
+
+fn argument_count(kind: &str) -> XlsxError {
+    XlsxError::ParameterError(format!("Wrong number of arguments for {}()", kind))
+}
+
+/// A single argument is a lower bound, two are the bounds of an interval.
+fn validation_bounds<T: IntoDataValidationValue>(
+    kind: &str,
+    args: &[Expr],
+    convert: impl Fn(&Expr) -> T,
+) -> Result<DataValidationRule<T>, XlsxError> {
+    match args {
+        [min] => Ok(DataValidationRule::GreaterThanOrEqualTo(convert(min))),
+        [min, max] => Ok(DataValidationRule::Between(convert(min), convert(max))),
+        _ => Err(argument_count(kind)),
+    }
+}
+
+fn validation_texts(kind: &str, args: &[Expr]) -> Result<(String, String), XlsxError> {
+    match args {
+        [message] => Ok((String::new(), message.as_str())),
+        [title, message, ..] => Ok((title.as_str(), message.as_str())),
+        _ => Err(argument_count(kind)),
+    }
+}
+
+fn cell_rule<T: IntoConditionalFormatValue>(
+    op: CompareOp,
+    value: T,
+) -> ConditionalFormatCellRule<T> {
+    match op {
+        CompareOp::Eq => ConditionalFormatCellRule::EqualTo(value),
+        CompareOp::Neq => ConditionalFormatCellRule::NotEqualTo(value),
+        CompareOp::Lt => ConditionalFormatCellRule::LessThan(value),
+        CompareOp::Gt => ConditionalFormatCellRule::GreaterThan(value),
+        CompareOp::Lte => ConditionalFormatCellRule::LessThanOrEqualTo(value),
+        CompareOp::Gte => ConditionalFormatCellRule::GreaterThanOrEqualTo(value),
+    }
+}
+
+fn interpret_icon_type(icons: &str) -> Option<ConditionalFormatIconType> {
+    let icon_type = match icons {
+        "3_arrows" => ConditionalFormatIconType::ThreeArrows,
+        "3_arrows_gray" => ConditionalFormatIconType::ThreeArrowsGray,
+        "3_flags" => ConditionalFormatIconType::ThreeFlags,
+        "3_traffic_lights" => ConditionalFormatIconType::ThreeTrafficLights,
+        "3_traffic_lights_rimmed" => ConditionalFormatIconType::ThreeTrafficLightsWithRim,
+        "3_signs" => ConditionalFormatIconType::ThreeSigns,
+        "3_symbols_circled" => ConditionalFormatIconType::ThreeSymbolsCircled,
+        "3_symbols" => ConditionalFormatIconType::ThreeSymbols,
+        "3_stars" => ConditionalFormatIconType::ThreeStars,
+        "3_triangles" => ConditionalFormatIconType::ThreeTriangles,
+        "4_arrows" => ConditionalFormatIconType::FourArrows,
+        "4_arrows_gray" => ConditionalFormatIconType::FourArrowsGray,
+        "4_red_to_black" => ConditionalFormatIconType::FourRedToBlack,
+        "4_histograms" => ConditionalFormatIconType::FourHistograms,
+        "4_traffic_lights" => ConditionalFormatIconType::FourTrafficLights,
+        "5_arrows" => ConditionalFormatIconType::FiveArrows,
+        "5_arrows_gray" => ConditionalFormatIconType::FiveArrowsGray,
+        "5_histograms" => ConditionalFormatIconType::FiveHistograms,
+        "5_quadrants" => ConditionalFormatIconType::FiveQuadrants,
+        "5_boxes" => ConditionalFormatIconType::FiveBoxes,
+        _ => return None,
+    };
+    Some(icon_type)
+}
+
+/// The parameter a format modifier takes.
+#[derive(Clone, Copy)]
+enum ModifierParam {
+    /// No parameter, like `bold`.
+    Flag,
+    Text,
+    Number,
+    Integer(i64, i64),
+    /// In degrees from -90 to 90, or 270 for stacked text.
+    Rotation,
+    Color,
+    /// One of the values, which may also be left out.
+    Choice(&'static [&'static str], bool),
+}
+
+const BORDER_STYLES: &[&str] = &[
+    "none",
+    "thin",
+    "medium",
+    "dashed",
+    "dotted",
+    "thick",
+    "double",
+    "hair",
+    "medium_dashed",
+    "dash_dot",
+    "medium_dash_dot",
+    "dash_dot_dot",
+    "medium_dash_dot_dot",
+    "slant_dash_dot",
+];
+
+const ALIGNMENTS: &[&str] = &[
+    "left",
+    "right",
+    "center",
+    "top",
+    "bottom",
+    "verticalcenter",
+    "general",
+    "fill",
+    "justify",
+    "center_across",
+    "distributed",
+    "verticaljustify",
+    "verticaldistributed",
+];
+
+const PATTERNS: &[&str] = &[
+    "none",
+    "solid",
+    "medium_gray",
+    "dark_gray",
+    "light_gray",
+    "dark_horizontal",
+    "dark_vertical",
+    "dark_down",
+    "dark_up",
+    "dark_grid",
+    "dark_trellis",
+    "light_horizontal",
+    "light_vertical",
+    "light_down",
+    "light_up",
+    "light_grid",
+    "light_trellis",
+    "gray_125",
+    "gray_0625",
+];
+
+const UNDERLINES: &[&str] = &[
+    "single",
+    "double",
+    "single_accounting",
+    "double_accounting",
+    "none",
+];
+
+/// The known format modifiers and their parameters.
+const FORMAT_MODIFIERS: &[(&str, ModifierParam)] = &[
+    ("bold", ModifierParam::Flag),
+    ("italic", ModifierParam::Flag),
+    ("underline", ModifierParam::Choice(UNDERLINES, true)),
+    ("strikethrough", ModifierParam::Flag),
+    ("super", ModifierParam::Flag),
+    ("sub", ModifierParam::Flag),
+    ("num", ModifierParam::Text),
+    ("align", ModifierParam::Choice(ALIGNMENTS, false)),
+    ("indent", ModifierParam::Integer(0, 250)),
+    ("wrap", ModifierParam::Flag),
+    ("shrink", ModifierParam::Flag),
+    ("rotation", ModifierParam::Rotation),
+    ("font_name", ModifierParam::Text),
+    ("font_size", ModifierParam::Number),
+    ("font_family", ModifierParam::Integer(0, 5)),
+    (
+        "font_scheme",
+        ModifierParam::Choice(&["minor", "major"], false),
+    ),
+    ("font_charset", ModifierParam::Integer(0, 255)),
+    ("color", ModifierParam::Color),
+    ("background_color", ModifierParam::Color),
+    ("foreground_color", ModifierParam::Color),
+    ("pattern", ModifierParam::Choice(PATTERNS, false)),
+    ("border", ModifierParam::Choice(BORDER_STYLES, false)),
+    ("border_top", ModifierParam::Choice(BORDER_STYLES, false)),
+    ("border_bottom", ModifierParam::Choice(BORDER_STYLES, false)),
+    ("border_left", ModifierParam::Choice(BORDER_STYLES, false)),
+    ("border_right", ModifierParam::Choice(BORDER_STYLES, false)),
+    ("border_color", ModifierParam::Color),
+    ("border_top_color", ModifierParam::Color),
+    ("border_bottom_color", ModifierParam::Color),
+    ("border_left_color", ModifierParam::Color),
+    ("border_right_color", ModifierParam::Color),
+    (
+        "diagonal_border",
+        ModifierParam::Choice(BORDER_STYLES, false),
+    ),
+    (
+        "diagonal_type",
+        ModifierParam::Choice(&["up", "down", "up_down", "none"], false),
+    ),
+    ("diagonal_border_color", ModifierParam::Color),
+    ("locked", ModifierParam::Flag),
+    ("unlocked", ModifierParam::Flag),
+    ("hidden", ModifierParam::Flag),
+];
+
+/// Checks a modifier of a format declaration against the catalogue and
+/// describes the problem, if any.
+fn check_modifier(format: &str, statement: &str, param: &str) -> Result<(), String> {
+    let Some((_, kind)) = FORMAT_MODIFIERS.iter().find(|(name, _)| *name == statement) else {
+        let mut message = format!("Unknown modifier in format {}: {}", format, statement);
+        if let Some(name) = closest_modifier(statement) {
+            message.push_str(&format!(", did you mean {}?", name));
+        }
+        return Err(message);
+    };
+    let expected = match kind {
+        ModifierParam::Flag if param.is_empty() => return Ok(()),
+        ModifierParam::Flag => "no parameter".to_string(),
+        ModifierParam::Text if !param.is_empty() => return Ok(()),
+        ModifierParam::Text => "a text".to_string(),
+        ModifierParam::Number if param.parse::<f64>().is_ok_and(|n| n > 0.0) => return Ok(()),
+        ModifierParam::Number => "a positive number".to_string(),
+        ModifierParam::Integer(min, max)
+            if param
+                .parse::<i64>()
+                .is_ok_and(|n| (*min..=*max).contains(&n)) =>
+        {
+            return Ok(())
+        }
+        ModifierParam::Integer(min, max) => format!("a whole number from {} to {}", min, max),
+        ModifierParam::Rotation
+            if param
+                .parse::<i64>()
+                .is_ok_and(|n| (-90..=90).contains(&n) || n == 270) =>
+        {
+            return Ok(())
+        }
+        ModifierParam::Rotation => "a whole number from -90 to 90, or 270".to_string(),
+        ModifierParam::Color if interpret_color(param).is_some() => return Ok(()),
+        ModifierParam::Color => {
+            "a color like \"#RRGGBB\", a color name or \"theme:<column>[:<shade>]\"".to_string()
+        }
+        ModifierParam::Choice(values, optional)
+            if values.contains(&param) || (*optional && param.is_empty()) =>
+        {
+            return Ok(())
+        }
+        ModifierParam::Choice(values, _) => format!("one of {}", values.join(", ")),
+    };
+    Err(format!(
+        "Invalid value for {}() in format {}: \"{}\", expected {}",
+        statement, format, param, expected
+    ))
+}
+
+/// The known modifier nearest to a misspelled one, within two edits.
+fn closest_modifier(statement: &str) -> Option<&'static str> {
+    FORMAT_MODIFIERS
+        .iter()
+        .map(|(name, _)| (edit_distance(statement, name), *name))
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, name)| name)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = previous[j] + usize::from(ca != *cb);
+            current.push(substitute.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Parses a color: `#RRGGBB` or `RRGGBB`, a name like `red`, or a theme
+/// color like `theme:4` or `theme:4:2` (the theme column and the shade, 0 to 5).
+fn interpret_color(color: &str) -> Option<Color> {
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    if hex.len() == 6 && hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return u32::from_str_radix(hex, 16).ok().map(Color::RGB);
+    }
+    if color.starts_with('#') {
+        return None;
+    }
+    if let Some(theme) = color.strip_prefix("theme:") {
+        let mut parts = theme.split(':');
+        let column = parts.next()?.parse::<u8>().ok().filter(|c| *c <= 9)?;
+        let shade = match parts.next() {
+            Some(shade) => shade.parse::<u8>().ok().filter(|s| *s <= 5)?,
+            None => 0,
+        };
+        return parts
+            .next()
+            .is_none()
+            .then_some(Color::Theme(column, shade));
+    }
+    let color = match color {
+        "black" => Color::Black,
+        "blue" => Color::Blue,
+        "brown" => Color::Brown,
+        "cyan" => Color::Cyan,
+        "gray" => Color::Gray,
+        "green" => Color::Green,
+        "lime" => Color::Lime,
+        "magenta" => Color::Magenta,
+        "navy" => Color::Navy,
+        "orange" => Color::Orange,
+        "pink" => Color::Pink,
+        "purple" => Color::Purple,
+        "red" => Color::Red,
+        "silver" => Color::Silver,
+        "white" => Color::White,
+        "yellow" => Color::Yellow,
+        "automatic" => Color::Automatic,
+        _ => return None,
+    };
+    Some(color)
+}
+
+fn interpret_underline(underline: &str) -> Option<FormatUnderline> {
+    match underline {
+        "" | "single" => Some(FormatUnderline::Single),
+        "double" => Some(FormatUnderline::Double),
+        "single_accounting" => Some(FormatUnderline::SingleAccounting),
+        "double_accounting" => Some(FormatUnderline::DoubleAccounting),
+        "none" => Some(FormatUnderline::None),
+        _ => None,
+    }
+}
+
+fn interpret_pattern(pattern: &str) -> Option<FormatPattern> {
+    let pattern = match pattern {
+        "none" => FormatPattern::None,
+        "solid" => FormatPattern::Solid,
+        "medium_gray" => FormatPattern::MediumGray,
+        "dark_gray" => FormatPattern::DarkGray,
+        "light_gray" => FormatPattern::LightGray,
+        "dark_horizontal" => FormatPattern::DarkHorizontal,
+        "dark_vertical" => FormatPattern::DarkVertical,
+        "dark_down" => FormatPattern::DarkDown,
+        "dark_up" => FormatPattern::DarkUp,
+        "dark_grid" => FormatPattern::DarkGrid,
+        "dark_trellis" => FormatPattern::DarkTrellis,
+        "light_horizontal" => FormatPattern::LightHorizontal,
+        "light_vertical" => FormatPattern::LightVertical,
+        "light_down" => FormatPattern::LightDown,
+        "light_up" => FormatPattern::LightUp,
+        "light_grid" => FormatPattern::LightGrid,
+        "light_trellis" => FormatPattern::LightTrellis,
+        "gray_125" => FormatPattern::Gray125,
+        "gray_0625" => FormatPattern::Gray0625,
+        _ => return None,
+    };
+    Some(pattern)
+}
+
+fn interpret_diagonal(diagonal: &str) -> Option<FormatDiagonalBorder> {
+    match diagonal {
+        "up" => Some(FormatDiagonalBorder::BorderUp),
+        "down" => Some(FormatDiagonalBorder::BorderDown),
+        "up_down" => Some(FormatDiagonalBorder::BorderUpDown),
+        "none" => Some(FormatDiagonalBorder::None),
+        _ => None,
+    }
+}
+
+fn interpret_border(border: &str) -> Option<FormatBorder> {
+    let border = match border {
+        "none" => FormatBorder::None,
+        "thin" => FormatBorder::Thin,
+        "medium" => FormatBorder::Medium,
+        "dashed" => FormatBorder::Dashed,
+        "dotted" => FormatBorder::Dotted,
+        "thick" => FormatBorder::Thick,
+        "double" => FormatBorder::Double,
+        "hair" => FormatBorder::Hair,
+        "medium_dashed" => FormatBorder::MediumDashed,
+        "dash_dot" => FormatBorder::DashDot,
+        "medium_dash_dot" => FormatBorder::MediumDashDot,
+        "dash_dot_dot" => FormatBorder::DashDotDot,
+        "medium_dash_dot_dot" => FormatBorder::MediumDashDotDot,
+        "slant_dash_dot" => FormatBorder::SlantDashDot,
+        _ => return None,
+    };
+    Some(border)
+}
+
+fn interpret_align(align: &str) -> Option<FormatAlign> {
+    let align = match align {
+        "left" => FormatAlign::Left,
+        "right" => FormatAlign::Right,
+        "center" => FormatAlign::Center,
+        "top" => FormatAlign::Top,
+        "bottom" => FormatAlign::Bottom,
+        "verticalcenter" => FormatAlign::VerticalCenter,
+        "general" => FormatAlign::General,
+        "fill" => FormatAlign::Fill,
+        "justify" => FormatAlign::Justify,
+        "center_across" => FormatAlign::CenterAcross,
+        "distributed" => FormatAlign::Distributed,
+        "verticaljustify" => FormatAlign::VerticalJustify,
+        "verticaldistributed" => FormatAlign::VerticalDistributed,
+        _ => return None,
+    };
+    Some(align)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::parser::parse_stream;
+    use crate::engine::vm::VM;
+
+    /// The XML parts of the workbook written for a template.
+    fn write(name: &str, template: &str) -> Result<BTreeMap<String, String>, SpreadSheetError> {
+        let path = std::env::temp_dir().join(format!(
+            "spreadsheet_builder_xlsx_{}_{}.xlsx",
+            name,
+            std::process::id()
+        ));
+        let tree = parse_stream(template).unwrap();
+        let mut writer = XlsxWriter::default();
+        VM::default().run(&tree.elements, &mut writer)?;
+        writer.save(path.to_str().unwrap()).unwrap();
+        let mut archive = ZipArchive::new(std::fs::File::open(&path).unwrap()).unwrap();
+        let mut parts = BTreeMap::new();
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i).unwrap();
+            if file.name().ends_with(".xml") {
+                let mut xml = String::new();
+                file.read_to_string(&mut xml).unwrap();
+                parts.insert(file.name().to_string(), xml);
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+        Ok(parts)
+    }
+
+    #[test]
+    fn conditional_formats_cover_their_range() {
+        let parts = write(
+            "conditional",
+            r##"
+:good { color("#006100") }
+sheet("S")
+anchor(@top)
+[ num(1), num(200) ]
+[ num(300), num(4) ]
+anchor(@bottom)
+conditional(@top, 0, 0, @bottom, -1, 1) {
+  when cell > 100 use :good;
+  data_bar("#63BE7B")
+}
+"##,
+        )
+        .unwrap();
+        let sheet = &parts["xl/worksheets/sheet1.xml"];
+        assert!(sheet.contains(r#"<conditionalFormatting sqref="A1:B2">"#));
+        assert!(sheet.contains(r#"operator="greaterThan"><formula>100</formula>"#));
+        assert!(sheet
+            .contains(r#"<dataBar><cfvo type="min"/><cfvo type="max"/><color rgb="FF63BE7B"/>"#));
+    }
+
+    #[test]
+    fn conditional_formats_refuse_anchors_of_other_sheets() {
+        let result = write(
+            "conditional_sheets",
+            r##"
+sheet("A")
+anchor(@top)
+[ num(1) ]
+sheet("B")
+[ num(2) ]
+conditional(@top, 0, 0) {
+  data_bar
+}
+"##,
+        );
+        assert!(result.unwrap_err().to_string().contains("another sheet"));
+    }
+
+    #[test]
+    fn decodes_base64_with_and_without_padding() {
+        assert_eq!(decode_base64("TWFu").unwrap(), b"Man");
+        assert_eq!(decode_base64("TWE=").unwrap(), b"Ma");
+        assert_eq!(decode_base64("TWE").unwrap(), b"Ma");
+        assert_eq!(decode_base64("TQ==").unwrap(), b"M");
+        assert_eq!(decode_base64("TQ").unwrap(), b"M");
+        assert_eq!(decode_base64(" TW\nFu ").unwrap(), b"Man");
+        assert_eq!(decode_base64("").unwrap(), b"");
+    }
+
+    #[test]
+    fn decodes_both_base64_alphabets() {
+        assert_eq!(decode_base64("+/+/").unwrap(), [0xfb, 0xff, 0xbf]);
+        assert_eq!(decode_base64("-_-_").unwrap(), [0xfb, 0xff, 0xbf]);
+    }
+
+    #[test]
+    fn rejects_invalid_base64() {
+        for data in [
+            "TWFuT", // a partial sextet
+            "TWE==", // padding to an invalid length
+            "TQ===", // too much padding
+            "T===",  // padding after a single character
+            "TR==",  // bits left after the last byte
+            "+/-_",  // mixed alphabets
+            "TW!u",  // not base64
+        ] {
+            assert!(decode_base64(data).is_err(), "{}", data);
+        }
+    }
+
+    #[test]
+    fn interprets_colors() {
+        assert_eq!(interpret_color("#FF0000"), Some(Color::RGB(0xFF0000)));
+        assert_eq!(interpret_color("FF0000"), Some(Color::RGB(0xFF0000)));
+        assert_eq!(interpret_color("00ff7f"), Some(Color::RGB(0x00FF7F)));
+        assert_eq!(interpret_color("red"), Some(Color::Red));
+        assert_eq!(interpret_color("theme:4:2"), Some(Color::Theme(4, 2)));
+        for color in ["#FF00", "#+FFFFF", "+FFFFF", "FF00000", "#red", "reddish"] {
+            assert_eq!(interpret_color(color), None, "{}", color);
+        }
+    }
+
+    fn zip(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in files {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn protects_the_workbook_structure_before_the_book_views() {
+        let buffer = zip(&[("xl/workbook.xml", "<workbook><sheets/></workbook>")]);
+        assert!(protect_workbook_structure(&buffer, None).is_err());
+        let buffer = zip(&[("xl/styles.xml", "<styleSheet/>")]);
+        assert!(protect_workbook_structure(&buffer, None).is_err());
+
+        let buffer = zip(&[(
+            "xl/workbook.xml",
+            "<workbook><bookViews></bookViews></workbook>",
+        )]);
+        let output = protect_workbook_structure(&buffer, None).unwrap();
+        let mut xml = String::new();
+        ZipArchive::new(Cursor::new(output))
+            .unwrap()
+            .by_name("xl/workbook.xml")
+            .unwrap()
+            .read_to_string(&mut xml)
+            .unwrap();
+        assert_eq!(
+            xml,
+            r#"<workbook><workbookProtection lockStructure="1"/><bookViews></bookViews></workbook>"#
+        );
+    }
+
+    #[test]
+    fn validations_cover_their_range() {
+        let parts = write(
+            "validation",
+            r##"
+sheet("S")
+anchor(@top)
+[ str("Status"), str("Count") ]
+[ str("Open"), num(1) ]
+[ str("Closed"), num(2) ]
+anchor(@bottom)
+validate(@top, 1, 0, @bottom, -1, 0) {
+  list("Open", "Closed");
+  error("Status", "Unknown status", "warning")
+}
+validate(@top, 1, 1, @bottom, -1, 1) {
+  whole_number(0, 10)
+}
+"##,
+        )
+        .unwrap();
+        let sheet = &parts["xl/worksheets/sheet1.xml"];
+        assert!(sheet.contains(r#"<dataValidations count="2">"#));
+        assert!(sheet.contains(r#"errorTitle="Status" error="Unknown status" sqref="A2:A3"><formula1>"Open,Closed"</formula1>"#));
+        assert!(sheet.contains(r#"sqref="B2:B3"><formula1>0</formula1><formula2>10</formula2>"#));
+    }
+
+    #[test]
+    fn validations_refuse_anchors_of_other_sheets() {
+        let result = write(
+            "validation_sheets",
+            r##"
+sheet("A")
+anchor(@top)
+[ num(1) ]
+sheet("B")
+validate(@top, 0, 0) {
+  whole_number(0, 10)
+}
+"##,
+        );
+        assert!(result.unwrap_err().to_string().contains("another sheet"));
+    }
+
+    #[test]
+    fn page_setup_resolves_its_ranges() {
+        let parts = write(
+            "page",
+            r##"
+sheet("S")
+anchor(@header)
+[ str("A"), str("B") ]
+[ num(1), num(2) ]
+anchor(@bottom)
+page {
+  landscape;
+  print_area(@header, 0, 0, @bottom, -1, 1);
+  repeat_rows(@header);
+  footer("&CPage &P of &N")
+}
+"##,
+        )
+        .unwrap();
+        let workbook = &parts["xl/workbook.xml"];
+        assert!(workbook.contains(
+            r#"<definedName name="_xlnm.Print_Area" localSheetId="0">S!$A$1:$B$2</definedName>"#
+        ));
+        assert!(workbook.contains(
+            r#"<definedName name="_xlnm.Print_Titles" localSheetId="0">S!$1:$1</definedName>"#
+        ));
+        let sheet = &parts["xl/worksheets/sheet1.xml"];
+        assert!(sheet.contains(r#"<pageSetup orientation="landscape""#));
+        assert!(sheet.contains("<oddFooter>&amp;CPage &amp;P of &amp;N</oddFooter>"));
+    }
+
+    #[test]
+    fn page_setup_refuses_anchors_of_other_sheets() {
+        let result = write(
+            "page_sheets",
+            r##"
+sheet("A")
+anchor(@top)
+[ num(1) ]
+sheet("B")
+[ num(2) ]
+page {
+  print_area(@top, 0, 0)
+}
+"##,
+        );
+        assert!(result.unwrap_err().to_string().contains("another sheet"));
+    }
+
+    #[test]
+    fn autofilter_hides_the_rows_filtered_out() {
+        let parts = write(
+            "autofilter",
+            r##"
+sheet("S")
+anchor(@top)
+[ str("Region"), str("Amount") ]
+[ str("East"), num(50) ]
+[ str("West"), num(500) ]
+[ str("North"), num(700) ]
+anchor(@bottom)
+autofilter(@top, @bottom, -1, 1) {
+  column(0) in ("East", "West");
+  column(1) > 100
+}
+"##,
+        )
+        .unwrap();
+        let sheet = &parts["xl/worksheets/sheet1.xml"];
+        assert!(sheet.contains(r#"<autoFilter ref="A1:B4">"#));
+        assert!(sheet.contains(r#"<filters><filter val="East"/><filter val="West"/></filters>"#));
+        assert!(sheet.contains(r#"<customFilter operator="greaterThan" val="100"/>"#));
+        assert!(sheet.contains(r#"<row r="2" spans="1:2" hidden="1">"#));
+        assert!(sheet.contains(r#"<row r="3" spans="1:2">"#));
+        assert!(sheet.contains(r#"<row r="4" spans="1:2" hidden="1">"#));
+    }
+
+    #[test]
+    fn autofilter_refuses_anchors_of_other_sheets() {
+        let result = write(
+            "autofilter_sheets",
+            r##"
+sheet("A")
+anchor(@top)
+[ str("Region") ]
+sheet("B")
+[ str("Region") ]
+autofilter(@top, 0, 0)
+"##,
+        );
+        assert!(result.unwrap_err().to_string().contains("another sheet"));
+    }
+
+    #[test]
+    fn border_and_fill_ranges_keep_the_cell_formats() {
+        let parts = write(
+            "border_fill",
+            r##"
+:money { num("#,##0.00") }
+sheet("S")
+anchor(@from)
+[ str("a"), num(1.5, :money) ]
+[ str("b"), num(2) ]
+anchor(@to)
+border_range(@from, @to, -1, 1, "medium", "#FF0000")
+fill_range(@from, 1, 0, @to, -1, 1, "#F2F2F2")
+"##,
+        )
+        .unwrap();
+        let styles = &parts["xl/styles.xml"];
+        assert!(styles.contains(r##"<numFmt numFmtId="164" formatCode="#,##0.00"/>"##));
+        assert!(styles.contains(r#"<fgColor rgb="FFF2F2F2"/>"#));
+        // The top left corner, the money cell at the top right, the
+        // filled bottom left corner.
+        for xf in [
+            r#"<xf numFmtId="0" fontId="0" fillId="0" borderId="1" xfId="0" applyBorder="1"/>"#,
+            r#"<xf numFmtId="164" fontId="0" fillId="0" borderId="2" xfId="0" applyNumberFormat="1" applyBorder="1"/>"#,
+            r#"<xf numFmtId="0" fontId="0" fillId="2" borderId="3" xfId="0" applyFill="1" applyBorder="1"/>"#,
+        ] {
+            assert!(styles.contains(xf), "{}", xf);
+        }
+        assert!(styles.contains(
+            r#"<border><left style="medium"><color rgb="FFFF0000"/></left><right/><top style="medium"><color rgb="FFFF0000"/></top><bottom/><diagonal/></border>"#
+        ));
+    }
+
+    #[test]
+    fn border_and_fill_ranges_refuse_anchors_of_other_sheets() {
+        for statement in [
+            r##"border_range(@from, @to, "thin")"##,
+            r##"fill_range(@from, @to, "#F2F2F2")"##,
+        ] {
+            let template = format!(
+                "sheet(\"A\")\nanchor(@from)\n[ num(1) ]\nanchor(@to)\nsheet(\"B\")\n[ num(2) ]\n{}\n",
+                statement
+            );
+            let result = write("border_fill_sheets", &template);
+            assert!(result.unwrap_err().to_string().contains("another sheet"));
+        }
+    }
+
+    #[test]
+    fn zooms_within_the_range_of_excel() {
+        let parts = write("zoom", "sheet(\"S\", zoom(85))\n[ num(1) ]\n").unwrap();
+        assert!(parts["xl/worksheets/sheet1.xml"].contains(r#"zoomScale="85""#));
+
+        for zoom in ["5", "401", "85.5", "\"85\""] {
+            let template = format!("sheet(\"S\", zoom({}))\n[ num(1) ]\n", zoom);
+            let result = write("zoom_invalid", &template);
+            assert!(result.unwrap_err().to_string().contains("Invalid zoom"));
+        }
+    }
+
+    #[test]
+    fn protects_the_workbook_structure() {
+        let parts = write("protect_workbook", "sheet(\"S\")\nprotect_workbook\n").unwrap();
+        assert!(parts["xl/workbook.xml"]
+            .contains(r#"<workbookProtection lockStructure="1"/><bookViews"#));
+
+        let parts = write(
+            "protect_workbook_password",
+            "sheet(\"S\")\nprotect_workbook(password: \"wb\")\n",
+        )
+        .unwrap();
+        let protection = format!(
+            r#"<workbookProtection workbookPassword="{:04X}" lockStructure="1"/><bookViews"#,
+            hash_password("wb")
+        );
+        assert!(parts["xl/workbook.xml"].contains(&protection));
+    }
+
+    #[test]
+    fn protects_the_sheet_with_the_allowed_options() {
+        let parts = write(
+            "protect_sheet",
+            "sheet(\"S\")\nprotect(password: \"secret\", allow: [select_unlocked, sort])\n",
+        )
+        .unwrap();
+        let sheet = &parts["xl/worksheets/sheet1.xml"];
+        assert!(sheet.contains(r#"selectLockedCells="1""#));
+        assert!(sheet.contains(r#"sort="0""#));
+
+        let mut writer = XlsxWriter {
+            worksheet: Some(Worksheet::new()),
+            ..Default::default()
+        };
+        let protect = Protect {
+            password: None,
+            allow: Some(vec!["sort", "paint"]),
+        };
+        let error = writer.process_protect(&protect).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Unknown protection option: paint"));
     }
 }
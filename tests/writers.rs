@@ -0,0 +1,130 @@
+//! The XLSX and CSV writers place the cells of a template at the same
+//! positions.
+
+use spreadsheet_builder::csv::CsvWriter;
+use spreadsheet_builder::engine::parser::parse_stream;
+use spreadsheet_builder::engine::vm::VM;
+use spreadsheet_builder::xlsx::XlsxWriter;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+
+/// The non-empty cells of a sheet by their zero based row and column.
+type Cells = BTreeMap<(u32, u32), String>;
+
+const TEMPLATE: &str = r#"
+sheet("First")
+anchor(@top)
+[ num(1), num(2, colspan(2)), num(3) ]
+move(1, 2)
+[ num(4, rowspan(2)), num(5) ]
+cr
+[ num(6) ]
+move(@top, 0, 6)
+[ num(7) ]
+anchor(@side)
+move(@side, 3, 1)
+[ num(8), num(9, colspan(2), rowspan(2)), num(10) ]
+sheet("Second")
+move(2, 3)
+[ num(11) ]
+cr
+[ num(anchor(@side).row), num($cursor.row) ]
+"#;
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "spreadsheet_builder_writers_{}_{}",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The zero based row and column of a cell reference such as `B3`.
+fn cell_position(reference: &str) -> (u32, u32) {
+    let letters = reference.bytes().take_while(|c| c.is_ascii_uppercase());
+    let col = letters.fold(0, |col, c| col * 26 + u32::from(c - b'A' + 1)) - 1;
+    let digits = reference.trim_start_matches(|c: char| c.is_ascii_uppercase());
+    (digits.parse::<u32>().unwrap() - 1, col)
+}
+
+/// The number cells of the worksheet XML of an XLSX file.
+fn xlsx_cells(path: &PathBuf, sheet: usize) -> Cells {
+    let mut archive = zip::ZipArchive::new(fs::File::open(path).unwrap()).unwrap();
+    let mut xml = String::new();
+    archive
+        .by_name(&format!("xl/worksheets/sheet{}.xml", sheet))
+        .unwrap()
+        .read_to_string(&mut xml)
+        .unwrap();
+    let mut cells = Cells::new();
+    for cell in xml.split("<c r=\"").skip(1) {
+        let (reference, rest) = cell.split_once('"').unwrap();
+        // Blank cells, e.g. the rest of a merged range, have no value.
+        if let Some((_, value)) = rest.split_once("<v>") {
+            let (value, _) = value.split_once("</v>").unwrap();
+            cells.insert(cell_position(reference), value.to_string());
+        }
+    }
+    cells
+}
+
+/// The non-empty fields of a CSV file.
+fn csv_cells(path: &PathBuf) -> Cells {
+    let mut cells = Cells::new();
+    for (row, line) in fs::read_to_string(path).unwrap().lines().enumerate() {
+        for (col, field) in line.split(',').enumerate() {
+            if !field.is_empty() {
+                cells.insert((row as u32, col as u32), field.to_string());
+            }
+        }
+    }
+    cells
+}
+
+#[test]
+fn xlsx_and_csv_place_cells_alike() {
+    let dir = temp_dir("alike");
+    let tree = parse_stream(TEMPLATE).unwrap();
+
+    let mut xlsx = XlsxWriter::default();
+    VM::default().run(&tree.elements, &mut xlsx).unwrap();
+    let xlsx_path = dir.join("out.xlsx");
+    xlsx.save(xlsx_path.to_str().unwrap()).unwrap();
+
+    let pattern = dir.join("{sheet}.csv");
+    let mut csv = CsvWriter::per_sheet(pattern.to_str().unwrap(), b',');
+    VM::default().run(&tree.elements, &mut csv).unwrap();
+    csv.save().unwrap();
+
+    let first = xlsx_cells(&xlsx_path, 1);
+    let expected: Cells = [
+        ((0, 0), "1"),
+        ((0, 1), "2"),
+        ((0, 3), "3"),
+        ((2, 2), "4"),
+        ((2, 3), "5"),
+        ((4, 0), "6"),
+        ((0, 6), "7"),
+        ((4, 7), "8"),
+        ((4, 8), "9"),
+        ((4, 10), "10"),
+    ]
+    .into_iter()
+    .map(|(position, value)| (position, value.to_string()))
+    .collect();
+    assert_eq!(first, expected);
+    assert_eq!(csv_cells(&dir.join("First.csv")), first);
+
+    let second = xlsx_cells(&xlsx_path, 2);
+    assert_eq!(second.get(&(2, 3)).map(String::as_str), Some("11"));
+    assert_eq!(second.get(&(4, 0)).map(String::as_str), Some("1"));
+    assert_eq!(second.get(&(4, 1)).map(String::as_str), Some("4"));
+    assert_eq!(csv_cells(&dir.join("Second.csv")), second);
+
+    fs::remove_dir_all(dir).unwrap();
+}